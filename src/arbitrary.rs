@@ -0,0 +1,94 @@
+//! [`quickcheck`] generators for segments, for testing code that consumes
+//! layouts
+use crate::segment::{Segment, SegmentType};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use quickcheck::{Arbitrary, Gen};
+
+impl Arbitrary for SegmentType {
+    fn arbitrary(g: &mut Gen) -> Self {
+        if bool::arbitrary(g) {
+            SegmentType::Hole
+        } else {
+            SegmentType::Data
+        }
+    }
+}
+
+/// A single non-empty segment somewhere in the first 4GiB, use
+/// [`CanonicalLayout`] for a whole file
+impl Arbitrary for Segment {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let start = u32::arbitrary(g) as u64;
+        let len = u16::arbitrary(g) as u64 + 1;
+        Segment {
+            segment_type: SegmentType::arbitrary(g),
+            range: start..start + len,
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let parts = (
+            self.segment_type,
+            self.range.start as u32,
+            (self.range.end - self.range.start - 1) as u16,
+        );
+        Box::new(parts.shrink().map(|(segment_type, start, len)| {
+            let start = start as u64;
+            Segment {
+                segment_type,
+                range: start..start + len as u64 + 1,
+            }
+        }))
+    }
+}
+
+/// An arbitrary layout of a whole file, as [`scan_chunks`] would return it
+///
+/// The segments are non-empty, start at 0, each starts where the previous one
+/// ended and they alternate between `Data` and `Hole`.
+///
+/// [`scan_chunks`]: crate::SparseFile::scan_chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalLayout(pub Vec<Segment>);
+
+impl CanonicalLayout {
+    fn from_parts(start_type: SegmentType, lens: &[u16]) -> Self {
+        let mut segment_type = start_type;
+        let mut start = 0;
+        let segments = lens
+            .iter()
+            .map(|len| {
+                let end = start + *len as u64 + 1;
+                let segment = Segment {
+                    segment_type,
+                    range: start..end,
+                };
+                segment_type = segment_type.opposite();
+                start = end;
+                segment
+            })
+            .collect();
+        CanonicalLayout(segments)
+    }
+}
+
+impl Arbitrary for CanonicalLayout {
+    fn arbitrary(g: &mut Gen) -> Self {
+        CanonicalLayout::from_parts(SegmentType::arbitrary(g), &Vec::arbitrary(g))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let start_type = self.0.first().map_or(SegmentType::Data, |s| s.segment_type);
+        let lens: Vec<u16> = self
+            .0
+            .iter()
+            .map(|s| (s.range.end - s.range.start - 1) as u16)
+            .collect();
+        Box::new(
+            lens.shrink()
+                .map(move |lens| CanonicalLayout::from_parts(start_type, &lens)),
+        )
+    }
+}
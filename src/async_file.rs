@@ -0,0 +1,45 @@
+//! Non-blocking versions of the core [`SparseFile`] operations for tokio
+use super::*;
+
+use std::future::Future;
+
+/// An extention trait for [`tokio::fs::File`] for sparse files
+///
+/// The underlying syscalls are blocking, so each operation is run on tokio's
+/// blocking thread pool with [`spawn_blocking`](tokio::task::spawn_blocking),
+/// on a duplicate of the file's descriptor. The results are exactly those of
+/// the [`SparseFile`] methods of the same name.
+pub trait AsyncSparseFile {
+    /// See [`SparseFile::scan_chunks`]
+    fn scan_chunks(&mut self) -> impl Future<Output = Result<Vec<Segment>, ScanError>> + Send;
+
+    /// See [`SparseFile::drill_hole`]
+    fn drill_hole(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> impl Future<Output = Result<(), ScanError>> + Send;
+}
+
+impl AsyncSparseFile for tokio::fs::File {
+    async fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        with_std_file(self, |file| file.scan_chunks()).await
+    }
+
+    async fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        with_std_file(self, move |file| file.drill_hole(start, end)).await
+    }
+}
+
+/// Runs `f` on the blocking thread pool with a [`File`] sharing the
+/// descriptor of `file`, once any operations in flight on it have finished
+async fn with_std_file<R, F>(file: &tokio::fs::File, f: F) -> Result<R, ScanError>
+where
+    R: Send + 'static,
+    F: FnOnce(&mut File) -> Result<R, ScanError> + Send + 'static,
+{
+    let mut std_file = file.try_clone().await?.into_std().await;
+    tokio::task::spawn_blocking(move || f(&mut std_file))
+        .await
+        .map_err(std::io::Error::other)?
+}
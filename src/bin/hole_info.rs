@@ -2,16 +2,113 @@ use drill_press::*;
 
 use std::env;
 use std::fs::File;
+use std::process::ExitCode;
 
-fn main() -> Result<(), ScanError> {
-    let args: Vec<String> = env::args().collect();
-    assert!(args.len() > 1);
-    println!("{}", args[1]);
-    let mut file = File::open(&args[1])?;
+fn main() -> ExitCode {
+    let mut json = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("usage: hole_info [--json] <path>...");
+        return ExitCode::FAILURE;
+    }
+
+    if json && cfg!(not(feature = "json")) {
+        eprintln!("--json needs hole_info to be built with the json feature");
+        return ExitCode::FAILURE;
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for path in paths {
+        let result = if json {
+            print_json(&path)
+        } else {
+            print_file(&path)
+        };
+        if let Err(e) = result {
+            match std::error::Error::source(&e) {
+                Some(source) => eprintln!("{}: {}: {}", path, e, source),
+                None => eprintln!("{}: {}", path, e),
+            }
+            status = ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+fn print_file(path: &str) -> Result<(), ScanError> {
+    println!("{}", path);
+    let mut file = File::open(path)?;
     let chunks = file.scan_chunks()?;
-    for chunk in chunks {
-        println!("{:?}", chunk);
+    for chunk in &chunks {
+        println!("{}", chunk);
+    }
+
+    let logical_len = chunks.total_len();
+    let sparse = if logical_len == 0 {
+        0.0
+    } else {
+        chunks.hole_len() as f64 * 100.0 / logical_len as f64
+    };
+    println!(
+        "{} data segments, {} holes, {} bytes logical, {} bytes allocated, {:.1}% sparse",
+        chunks.data().count(),
+        chunks.holes().count(),
+        logical_len,
+        file.sparse_len()?,
+        sparse
+    );
+
+    Ok(())
+}
+
+/// Prints the layout of the file as a single line JSON document
+#[cfg(feature = "json")]
+fn print_json(path: &str) -> Result<(), ScanError> {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct JsonFile<'a> {
+        path: &'a str,
+        logical_len: u64,
+        segments: Vec<JsonSegment>,
     }
 
+    #[derive(Serialize)]
+    struct JsonSegment {
+        #[serde(rename = "type")]
+        segment_type: SegmentType,
+        start: u64,
+        length: u64,
+    }
+
+    let chunks = scan_path(path)?;
+    let document = JsonFile {
+        path,
+        logical_len: chunks.total_len(),
+        segments: chunks
+            .iter()
+            .map(|s| JsonSegment {
+                segment_type: s.segment_type,
+                start: s.start(),
+                length: s.len(),
+            })
+            .collect(),
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&document).map_err(std::io::Error::from)?
+    );
+
     Ok(())
 }
+
+#[cfg(not(feature = "json"))]
+fn print_json(_path: &str) -> Result<(), ScanError> {
+    unreachable!("--json is rejected without the json feature")
+}
@@ -14,4 +14,12 @@ impl SparseFile for File {
     fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
         Err(ScanError::UnsupportedPlatform)
     }
+
+    fn io_block_size(&self) -> Result<u64, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
 }
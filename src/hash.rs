@@ -0,0 +1,108 @@
+//! Hashing the logical content of a sparse file, however its zeros are stored
+use super::*;
+
+use std::hash::Hasher;
+
+/// How many bytes are read, and handed to the hasher, at a time
+const CHUNK: usize = 64 * 1024;
+
+/// Feeds the content of `file` to `hasher`, reading only its `Data` segments
+///
+/// See [`hash_data`](SparseFile::hash_data).
+pub(crate) fn hash_data<F, H>(file: &mut F, hasher: &mut H) -> Result<(), ScanError>
+where
+    F: SparseFile + ?Sized,
+    H: Hasher,
+{
+    let segments = file.scan_chunks()?;
+    let mut content = ContentHasher::new(hasher);
+    let mut buffer = vec![0_u8; CHUNK];
+
+    for segment in segments {
+        if segment.is_hole() {
+            content.zeros(segment.len());
+            continue;
+        }
+        file.seek(SeekFrom::Start(segment.start()))?;
+        let mut remaining = segment.len();
+        while remaining > 0 {
+            let want = remaining.min(CHUNK as u64) as usize;
+            file.read_exact(&mut buffer[..want])?;
+            content.bytes(&buffer[..want]);
+            remaining -= want as u64;
+        }
+    }
+    content.finish();
+    Ok(())
+}
+
+/// Feeds a canonical encoding of some content to a [`Hasher`]
+///
+/// Non-zero bytes are passed through as they are, while each maximal run of
+/// zeros, whether read or from a hole, becomes a single 0 byte followed by
+/// the length of the run as a little endian u64. The encoding is handed to
+/// the hasher in chunks of a fixed size, so the hash doesn't depend on where
+/// the reads or segments happened to split the content either.
+struct ContentHasher<'h, H> {
+    hasher: &'h mut H,
+    buffer: Vec<u8>,
+    /// The length of the run of zeros not yet encoded
+    zeros: u64,
+}
+
+impl<'h, H: Hasher> ContentHasher<'h, H> {
+    fn new(hasher: &'h mut H) -> Self {
+        ContentHasher {
+            hasher,
+            buffer: Vec::with_capacity(CHUNK),
+            zeros: 0,
+        }
+    }
+
+    fn zeros(&mut self, len: u64) {
+        self.zeros += len;
+    }
+
+    fn bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let non_zero = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+            if non_zero > 0 {
+                self.flush_zeros();
+                self.push(&bytes[..non_zero]);
+            }
+            bytes = &bytes[non_zero..];
+
+            let zero = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+            self.zeros(zero as u64);
+            bytes = &bytes[zero..];
+        }
+    }
+
+    fn finish(mut self) {
+        self.flush_zeros();
+        if !self.buffer.is_empty() {
+            self.hasher.write(&self.buffer);
+        }
+    }
+
+    fn flush_zeros(&mut self) {
+        if self.zeros > 0 {
+            let mut marker = [0_u8; 9];
+            marker[1..].copy_from_slice(&self.zeros.to_le_bytes());
+            self.push(&marker);
+            self.zeros = 0;
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let take = (CHUNK - self.buffer.len()).min(bytes.len());
+            self.buffer.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.buffer.len() == CHUNK {
+                self.hasher.write(&self.buffer);
+                self.buffer.clear();
+            }
+        }
+    }
+}
@@ -1,15 +1,32 @@
-use std::io::{Read, Seek};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
 use std::ops::Range;
-use std::slice::Iter;
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::time::Instant;
+#[cfg(feature = "std")]
 use thiserror::Error;
 
+#[cfg(feature = "std")]
 cfg_if::cfg_if! {
     if #[cfg(any(target_os = "linux",
                  target_os = "android",
                  target_os = "freebsd",
                  target_os = "macos",
+                 target_os = "netbsd",
+                 target_os = "openbsd",
+                 target_os = "dragonfly",
     ))]{
         mod unix;
+        pub use unix::BorrowedFile;
     } else if #[cfg(windows)] {
         mod windows;
     } else {
@@ -17,9 +34,41 @@ cfg_if::cfg_if! {
     }
 }
 
-#[cfg(test)]
+#[cfg(feature = "tokio")]
+mod async_file;
+#[cfg(feature = "tokio")]
+pub use async_file::AsyncSparseFile;
+
+mod segment;
+pub use segment::{
+    apply_hole, coalesce, diff_segments, from_cluster_bitmap, intersect_layout, to_cluster_bitmap,
+    union_layout, with_prev, Change, ChangeKind, Segment, SegmentIter, SegmentType, Segments,
+};
+#[cfg(feature = "std")]
+use segment::{coalesced, push_segment};
+
+#[cfg(any(all(test, feature = "std"), feature = "quickcheck"))]
+mod arbitrary;
+#[cfg(feature = "quickcheck")]
+pub use arbitrary::CanonicalLayout;
+
+#[cfg(feature = "std")]
+mod hash;
+
+#[cfg(feature = "std")]
+mod reader;
+#[cfg(feature = "std")]
+pub use reader::SparseReader;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::ScanStats;
+
+#[cfg(all(test, feature = "std"))]
 mod test_utils;
 
+#[cfg(feature = "std")]
 #[derive(Error, Debug)]
 /// Errors returned by [`scan_chunks`](SparseFile::scan_chunks)
 pub enum ScanError {
@@ -31,104 +80,705 @@ pub enum ScanError {
     UnsupportedPlatform,
     #[error("The filesystem does not support operating on sparse files")]
     UnsupportedFileSystem,
+    #[error("The deadline passed before the scan completed, stopped at offset {offset}")]
+    TimedOut { offset: u64 },
+    #[error("Unable to determine the length of the device")]
+    UnknownLength,
+    #[error("The scan left the bytes {gap:?} uncovered or covered twice")]
+    NonContiguous { gap: Range<u64> },
+    #[error("There is not enough space left on the device")]
+    OutOfSpace,
+    #[error("The target is not a regular file or a device")]
+    NotARegularFile,
+    #[error("The range {start}..{end} ends before it starts")]
+    InvalidRange { start: u64, end: u64 },
+    #[error("The block size or granularity must not be zero")]
+    ZeroBlockSize,
+    #[error("Drilling range {index} ({range:?}) failed")]
+    DrillFailed {
+        /// The index of the range that failed, all those before it were
+        /// drilled
+        index: usize,
+        range: Range<u64>,
+        #[source]
+        source: Box<ScanError>,
+    },
 }
 
-/// Flag for determining if a segment is a hole, or if it contains data
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum SegmentType {
-    Hole,
-    Data,
-}
-
-impl SegmentType {
-    pub fn opposite(&self) -> Self {
+#[cfg(feature = "std")]
+impl ScanError {
+    /// The kind of the underlying I/O error, if this is `ScanError::IO`
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
         match self {
-            SegmentType::Hole => SegmentType::Data,
-            SegmentType::Data => SegmentType::Hole,
+            ScanError::IO(e) => Some(e.kind()),
+            _ => None,
         }
     }
 }
 
-/// Describes the location of a chunk in the file, as well as indicating if it
-/// contains data or is a hole
+/// Checks that a range given as `start` and `end` isn't backwards, before
+/// `end - start` can underflow
+#[cfg(feature = "std")]
+fn check_range(start: u64, end: u64) -> Result<(), ScanError> {
+    if start > end {
+        return Err(ScanError::InvalidRange { start, end });
+    }
+    Ok(())
+}
+
+/// Converts an error from writing to or allocating space in a file, picking
+/// out running out of space (`ENOSPC` or `ERROR_DISK_FULL`) as
+/// [`ScanError::OutOfSpace`]
+#[cfg(feature = "std")]
+fn allocation_error(error: std::io::Error) -> ScanError {
+    match error.kind() {
+        std::io::ErrorKind::StorageFull => ScanError::OutOfSpace,
+        _ => error.into(),
+    }
+}
+
+/// How the filesystem stores a segment, beyond whether it is a hole
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ExtentFlags {
+    /// The space is allocated but has never been written, so reads as zeros
+    pub unwritten: bool,
+    /// The space is shared with other files, e.g. by a reflink copy
+    pub shared: bool,
+    /// The data is encrypted by the filesystem
+    pub encrypted: bool,
+}
+
+/// A [`Segment`] along with how the filesystem stores it, as returned by
+/// [`scan_chunks_detailed`](SparseFile::scan_chunks_detailed)
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetailedSegment {
+    pub segment: Segment,
+    pub flags: ExtentFlags,
+}
+
+/// Where an extent of a file lives on disk, as returned by
+/// [`physical_extents`](SparseFile::physical_extents)
+#[cfg(feature = "std")]
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Segment {
-    /// Marks this segment as either containing a hole, or containing data
-    pub segment_type: SegmentType,
-    /// the (half-open) range of bytes in the file covered by this segment
-    pub range: Range<u64>,
+pub struct PhysicalExtent {
+    /// The offset of the extent in the file
+    pub logical: u64,
+    /// The offset of the extent on the underlying device
+    pub physical: u64,
+    /// The length of the extent in bytes
+    pub length: u64,
+    pub flags: ExtentFlags,
 }
 
-/// An iterator over the ranges of a file of a specific [`SegmentType`]
+/// An owning iterator over the ranges of the holes in a file, returned by
+/// [`holes_to_punch`](SparseFile::holes_to_punch)
+#[cfg(feature = "std")]
 #[derive(Debug, Clone)]
-pub struct SegmentIter<'a> {
-    segment_type: SegmentType,
-    iter: Iter<'a, Segment>,
+pub struct HoleRanges {
+    iter: std::vec::IntoIter<Segment>,
 }
 
-impl<'a> Iterator for SegmentIter<'a> {
-    type Item = &'a Range<u64>;
+#[cfg(feature = "std")]
+impl Iterator for HoleRanges {
+    type Item = Range<u64>;
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        for segment in self.iter.by_ref() {
-            if segment.segment_type == self.segment_type {
-                return Some(&segment.range);
+        self.iter.find(Segment::is_hole).map(|s| s.range)
+    }
+}
+
+/// A lazy scan of a file, returned by
+/// [`scan_chunks_iter`](SparseFile::scan_chunks_iter)
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct ScanIter<'a, F: ?Sized> {
+    file: &'a mut F,
+    /// Created on the first call to `next`, once the length is known
+    stepper: Option<SegmentStepper>,
+    deadline: Option<Instant>,
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<F: SparseFile + ?Sized> ScanIter<'_, F> {
+    fn next_segment(&mut self) -> Result<Option<Segment>, ScanError> {
+        let stepper = match &mut self.stepper {
+            Some(stepper) => stepper,
+            None => {
+                let end = self.file.scan_end()?;
+                self.stepper
+                    .insert(SegmentStepper::new(0..end, self.deadline))
             }
+        };
+        let file = &mut *self.file;
+        stepper.next(|offset, wanted| match wanted {
+            SegmentType::Data => file.seek_data(offset),
+            SegmentType::Hole => file.seek_hole(offset),
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl<F: SparseFile + ?Sized> Iterator for ScanIter<'_, F> {
+    type Item = Result<Segment, ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.done {
+            return None;
         }
-        None
+        let next = self.next_segment().transpose();
+        // Stop after the end, or the first error
+        self.done = !matches!(next, Some(Ok(_)));
+        next
     }
 }
 
-/// An extention trait to filter segments by Hole or Data segments
-pub trait Segments {
-    fn data(&self) -> SegmentIter;
-    fn holes(&self) -> SegmentIter;
+/// The seeking loop behind every scan, stepping from one boundary between
+/// data and holes to the next over a range of a file
+///
+/// The seeks themselves are passed in to each call of
+/// [`next`](SegmentStepper::next), as a function finding the next `Data` or
+/// `Hole` at or after an offset, so that the same loop can drive `lseek`,
+/// the [`SparseFile`] methods, or a mock.
+///
+/// A seek that goes nowhere would give a zero length segment, so each
+/// segment is held back until the next is found, with empty ones dropped and
+/// the segments either side of them merged. If both kinds of seek go nowhere
+/// the filesystem is contradicting itself, and
+/// `Err(ScanError::UnsupportedFileSystem)` is returned rather than looping
+/// forever.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub(crate) struct SegmentStepper {
+    offset: u64,
+    end: u64,
+    /// The type of the segment starting at `offset`, if known
+    segment_type: Option<SegmentType>,
+    pending: Option<Segment>,
+    /// Whether the last seek went nowhere
+    stalled: bool,
+    /// Checked before each seek
+    deadline: Option<Instant>,
+}
+
+#[cfg(feature = "std")]
+impl SegmentStepper {
+    pub(crate) fn new(range: Range<u64>, deadline: Option<Instant>) -> Self {
+        SegmentStepper {
+            offset: range.start,
+            end: range.end,
+            segment_type: None,
+            pending: None,
+            stalled: false,
+            deadline,
+        }
+    }
+
+    /// Finds the next segment, or `None` once the range is covered
+    ///
+    /// If the deadline passes, `Err(ScanError::TimedOut { offset })` is
+    /// returned with the start of the first segment not yet returned.
+    pub(crate) fn next<S>(&mut self, mut seek: S) -> Result<Option<Segment>, ScanError>
+    where
+        S: FnMut(u64, SegmentType) -> Result<Option<u64>, ScanError>,
+    {
+        while self.offset < self.end {
+            if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                let offset = self.pending.as_ref().map_or(self.offset, Segment::start);
+                return Err(ScanError::TimedOut { offset });
+            }
+
+            // Until the type is known, look for the first hole, anything
+            // before it is data
+            let segment_type = self.segment_type.unwrap_or(SegmentType::Data);
+            let next = seek(self.offset, segment_type.opposite())?
+                .unwrap_or(self.end)
+                .clamp(self.offset, self.end);
+            if self.segment_type.is_some() {
+                if next == self.offset && self.stalled {
+                    return Err(ScanError::UnsupportedFileSystem);
+                }
+                self.stalled = next == self.offset;
+            }
+
+            let segment = Segment {
+                segment_type,
+                range: self.offset..next,
+            };
+            self.offset = next;
+            self.segment_type = Some(segment_type.opposite());
+            if let Some(done) = self.push(segment) {
+                return Ok(Some(done));
+            }
+        }
+        Ok(self.pending.take())
+    }
+
+    /// Holds back `segment`, returning the one held before it unless they
+    /// can be merged
+    fn push(&mut self, segment: Segment) -> Option<Segment> {
+        if segment.is_empty() {
+            return None;
+        }
+        match &mut self.pending {
+            Some(held) if held.segment_type == segment.segment_type => {
+                held.range.end = segment.range.end;
+                None
+            }
+            pending => pending.replace(segment),
+        }
+    }
 }
 
-impl Segments for Vec<Segment> {
-    fn data(&self) -> SegmentIter {
-        SegmentIter {
-            segment_type: SegmentType::Data,
-            iter: self.iter(),
+/// Opens a file read-only, with the flags best suited to scanning it
+///
+/// On Linux this sets `O_NOATIME`, so that scanning large numbers of files
+/// does not churn their access times. Only the owner of a file (or a
+/// privileged user) may do so, so if that is refused the file is opened
+/// without it instead.
+#[cfg(feature = "std")]
+pub fn open_for_scan<P: AsRef<Path>>(path: P) -> Result<File, ScanError> {
+    let path = path.as_ref();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::fs::OpenOptions;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => return Ok(result?),
         }
     }
-    fn holes(&self) -> SegmentIter {
-        SegmentIter {
-            segment_type: SegmentType::Hole,
-            iter: self.iter(),
+
+    Ok(File::open(path)?)
+}
+
+/// Scans the file at `path`, opening it read-only with [`open_for_scan`]
+///
+/// The file is closed again before this returns.
+#[cfg(feature = "std")]
+pub fn scan_path<P: AsRef<Path>>(path: P) -> Result<Vec<Segment>, ScanError> {
+    open_for_scan(path)?.scan_chunks()
+}
+
+/// Replaces the file at `path` with a sparse copy of itself, returning the
+/// number of bytes saved
+///
+/// Rather than drilling holes into the live file, the data is copied into a
+/// new file alongside it, skipping any `block_size` blocks (aligned to the
+/// start of the file) that are holes or contain only zeros. The copy is given
+/// the metadata of the original (see [`copy_metadata`]), synced to disk,
+/// then renamed over the original. If anything fails before the rename the
+/// original is left untouched.
+///
+/// The bytes saved are the bytes of data in the original that were found to
+/// be zeros and left out of the copy.
+#[cfg(feature = "std")]
+pub fn sparsify_in_place_atomic(path: &Path, block_size: u64) -> Result<u64, ScanError> {
+    let mut src = File::open(path)?;
+    let (raw, verified) = src.scan_both(block_size)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (temp_path, mut dst) = create_temp_beside(dir, path)?;
+
+    let result = (|| -> Result<(), ScanError> {
+        dst.set_sparse()?;
+        let buffer_size = src.io_block_size()?;
+        copy_data(&mut src, &mut dst, &verified, buffer_size)?;
+        dst.set_len(verified.last().map(|s| s.range.end).unwrap_or(0))?;
+        copy_metadata(&src, &dst)?;
+        dst.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // Make sure the rename itself is durable
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+
+    Ok(raw.data_len() - verified.data_len())
+}
+
+/// How many names [`create_temp_beside`] tries before giving up
+#[cfg(feature = "std")]
+const MAX_TEMP_ATTEMPTS: usize = 64;
+
+/// Creates a new, empty file in `dir` to be renamed over `path`
+///
+/// The name is made from `path`, the process id and a counter, so concurrent
+/// calls never share a file. A name that is already taken, e.g. left behind
+/// by a crashed process that had the same id, is skipped.
+#[cfg(feature = "std")]
+fn create_temp_beside(dir: &Path, path: &Path) -> Result<(std::path::PathBuf, File), ScanError> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut attempts = 0;
+    loop {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(format!(
+            ".sparsify-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = dir.join(temp_name);
+
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AlreadyExists
+                    && attempts < MAX_TEMP_ATTEMPTS =>
+            {
+                attempts += 1
+            }
+            Err(e) => return Err(e.into()),
         }
     }
 }
 
-#[allow(clippy::len_without_is_empty)] // Segments should never be zero length
-impl Segment {
-    /// Returns true if the provided offset is within the range of bytes this
-    /// segment specifies
-    pub fn contains(&self, offset: &u64) -> bool {
-        self.range.contains(offset)
+/// Copies the metadata of `src` onto `dst`, for making faithful copies of
+/// files
+///
+/// This copies the permissions, access and modification times, on unix the
+/// ownership, and on Linux the extended attributes (which includes SELinux
+/// labels). Changing ownership and some extended attributes needs privileges
+/// the caller may lack, in which case the error is returned. If the
+/// filesystem of `dst` does not support extended attributes
+/// `Err(ScanError::UnsupportedFileSystem)` is returned.
+///
+/// The times are copied last, as writing to `dst` afterwards would update
+/// them again.
+#[cfg(feature = "std")]
+pub fn copy_metadata(src: &File, dst: &File) -> Result<(), ScanError> {
+    let metadata = src.metadata()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Changing the owner can clear setuid bits, so do it before setting
+        // the permissions
+        std::os::unix::fs::fchown(dst, Some(metadata.uid()), Some(metadata.gid()))?;
     }
+    dst.set_permissions(metadata.permissions())?;
 
-    /// Returns true if this segment is a Hole
-    pub fn is_hole(&self) -> bool {
-        self.segment_type == SegmentType::Hole
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        unix::copy_xattrs(src.as_raw_fd(), dst.as_raw_fd())?;
     }
 
-    /// Returns true if this segment contains data
-    pub fn is_data(&self) -> bool {
-        self.segment_type == SegmentType::Data
+    let times = std::fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    dst.set_times(times)?;
+    Ok(())
+}
+
+/// Copies the `Data` segments of `src` to the same offsets in `dst`, through
+/// a buffer of `buffer_size` bytes, returning the number of bytes copied
+#[cfg(feature = "std")]
+fn copy_data<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    segments: &[Segment],
+    buffer_size: u64,
+) -> std::io::Result<u64>
+where
+    R: Read + Seek + ?Sized,
+    W: Write + Seek + ?Sized,
+{
+    let mut buffer = vec![0_u8; buffer_size as usize];
+    let mut copied = 0;
+    for data in segments.iter().filter(|s| s.is_data()) {
+        src.seek(SeekFrom::Start(data.start()))?;
+        dst.seek(SeekFrom::Start(data.start()))?;
+        let mut offset = data.start();
+        while offset < data.range.end {
+            let chunk = &mut buffer[..(data.range.end - offset).min(buffer_size) as usize];
+            src.read_exact(chunk)?;
+            dst.write_all(chunk)?;
+            offset += chunk.len() as u64;
+        }
+        copied += data.len();
     }
+    Ok(copied)
+}
+
+/// Drills a hole in a file that is mapped writable, making sure reads through
+/// the mapping see zeros afterwards
+///
+/// `start` and `end` are offsets in the file, and `mmap` must map `file` from
+/// its start. After drilling the hole, on unix the kernel is advised
+/// (`MADV_DONTNEED`) that the whole pages of the mapping covering it are no
+/// longer needed, so they are faulted back in from the now empty file.
+#[cfg(feature = "memmap")]
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    )),
+    allow(unused_variables)
+)]
+pub fn drill_hole_mmap(
+    mmap: &mut memmap2::MmapMut,
+    file: &File,
+    start: u64,
+    end: u64,
+) -> Result<(), ScanError> {
+    file.drill_hole(start, end)?;
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    unix::discard_pages(mmap, start..end)?;
+
+    Ok(())
+}
+
+/// An in-memory model of the sparse structure of a file, as captured by
+/// [`to_layout`](SparseFile::to_layout)
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    segments: Vec<Segment>,
+}
 
-    /// The starting position of this segment
-    pub fn start(&self) -> u64 {
-        self.range.start
+#[cfg(feature = "std")]
+impl Layout {
+    /// The segments making up this layout, ordered by their start position
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
     }
 
-    /// The number of bytes in this segment
+    /// The logical length of the file this layout describes
     pub fn len(&self) -> u64 {
-        self.range.start - self.range.end
+        self.segments.last().map(|s| s.range.end).unwrap_or(0)
+    }
+
+    /// Returns true if this layout describes an empty file
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Unwraps this layout into its list of segments
+    pub fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Vec<Segment>> for Layout {
+    fn from(segments: Vec<Segment>) -> Self {
+        Layout { segments }
+    }
+}
+
+/// Options for [`scan_chunks_opts`](SparseFile::scan_chunks_opts)
+///
+/// The default is a plain [`scan_chunks`](SparseFile::scan_chunks).
+#[cfg(feature = "std")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// If the file is not sparse, read it and report runs of zero blocks as
+    /// holes rather than a single `Data` segment
+    ///
+    /// Gives an honest picture of how much of a dense file could be freed,
+    /// mainly on Windows where files not marked as sparse never have holes.
+    pub detect_zeros_in_nonsparse: bool,
+}
+
+/// A summary of the sparseness of a file, returned by
+/// [`report`](SparseFile::report)
+///
+/// Displays as a single line of `key=value` pairs.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// The apparent size of the file
+    pub logical_len: u64,
+    /// The total length of the `Data` segments
+    pub data_len: u64,
+    /// The total length of the `Hole` segments
+    pub hole_len: u64,
+    /// The space the file occupies on disk, see
+    /// [`sparse_len`](SparseFile::sparse_len)
+    pub allocated_len: u64,
+    /// The number of segments in the file
+    pub segment_count: usize,
+    /// See [`fragmentation_score`](SparseFile::fragmentation_score)
+    pub fragmentation_score: f64,
+    /// See [`block_size`](SparseFile::block_size)
+    pub block_size: u64,
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "logical_len={} data_len={} hole_len={} allocated_len={} segments={} \
+             fragmentation={:.4} block_size={}",
+            self.logical_len,
+            self.data_len,
+            self.hole_len,
+            self.allocated_len,
+            self.segment_count,
+            self.fragmentation_score,
+            self.block_size
+        )
+    }
+}
+
+/// Destinations whose length can be set, like [`File::set_len`], for
+/// [`copy_to`](SparseFile::copy_to)
+#[cfg(feature = "std")]
+pub trait SetLen {
+    /// Truncates or extends the destination to `len` bytes, with any new
+    /// bytes reading as zeros
+    fn set_len(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl SetLen for File {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl SetLen for std::io::Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// Writes files with a known sparse layout, e.g. for test fixtures
+///
+/// ```no_run
+/// use drill_press::{SparseFile, SparseFileBuilder};
+///
+/// let mut file = tempfile::tempfile()?;
+/// let segments = SparseFileBuilder::new()
+///     .data(4096)
+///     .hole(1 << 20)
+///     .data(8192)
+///     .write_to(&mut file)?;
+/// assert_eq!(file.scan_chunks()?, segments);
+/// # Ok::<(), drill_press::ScanError>(())
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct SparseFileBuilder {
+    segments: Vec<Segment>,
+}
+
+#[cfg(feature = "std")]
+impl SparseFileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a segment of `len` bytes of the given type
+    pub fn segment(mut self, segment_type: SegmentType, len: u64) -> Self {
+        if len == 0 {
+            return self;
+        }
+        let start = self.segments.last().map(|s| s.range.end).unwrap_or(0);
+        push_segment(&mut self.segments, segment_type, start..start + len);
+        self
+    }
+
+    /// Appends `len` bytes of data
+    pub fn data(self, len: u64) -> Self {
+        self.segment(SegmentType::Data, len)
+    }
+
+    /// Appends a hole of `len` bytes
+    pub fn hole(self, len: u64) -> Self {
+        self.segment(SegmentType::Hole, len)
+    }
+
+    /// Replaces the contents of `file` with the layout, returning the
+    /// segments written
+    ///
+    /// Data segments are filled with `0x01` bytes, so they are never mistaken
+    /// for zeros. On windows the file is marked as sparse first, otherwise it
+    /// could not have holes. Neighbouring segments of the same type are
+    /// merged and empty ones dropped, so the result can be compared directly
+    /// with [`scan_chunks`](SparseFile::scan_chunks), as long as the lengths
+    /// are multiples of the filesystem's [`block_size`](SparseFile::block_size).
+    pub fn write_to(&self, file: &mut File) -> Result<Vec<Segment>, ScanError> {
+        file.set_sparse()?;
+        file.set_len(0)?;
+        let buffer = vec![1_u8; 64 * 1024];
+        for data in self.segments.data() {
+            file.seek(SeekFrom::Start(data.start))?;
+            let mut remaining = data.end - data.start;
+            while remaining > 0 {
+                let chunk = remaining.min(buffer.len() as u64) as usize;
+                file.write_all(&buffer[..chunk])?;
+                remaining -= chunk as u64;
+            }
+        }
+        file.set_len(self.segments.total_len())?;
+
+        Ok(self.segments.clone())
+    }
+
+    /// Like [`write_to`](SparseFileBuilder::write_to), but then explicitly
+    /// drills each hole with [`drill_hole`](SparseFile::drill_hole)
+    ///
+    /// Some filesystems (notably APFS on macOS) may allocate parts of the
+    /// regions that were never written, so the holes only reliably show up
+    /// in a scan once they have been drilled.
+    pub fn build_drilled(&self, file: &mut File) -> Result<Vec<Segment>, ScanError> {
+        let segments = self.write_to(file)?;
+        for hole in segments.holes() {
+            file.drill_hole(hole.start, hole.end)?;
+        }
+        Ok(segments)
     }
 }
 
 /// An extention trait for [`File`](std::fs::File) for sparse files
+///
+/// On unix and Windows it is implemented for anything that can be read,
+/// seeked and gives access to its descriptor (`AsRawFd`) or handle
+/// (`AsRawHandle`), such as wrappers around a `File`. Scanning works on the
+/// descriptor directly, so a wrapper that buffers reads should be seeked
+/// with `SeekFrom::Start` before being read again.
+#[cfg(feature = "std")]
 pub trait SparseFile: Read + Seek {
     /// Scans the file to find its logical chunks
     ///
@@ -144,7 +794,8 @@ pub trait SparseFile: Read + Seek {
     /// details.
     ///
     /// Does not make any guarantee about maintaining the Seek position of the
-    /// file, always seek back to a known point after calling this method.
+    /// file, always seek back to a known point after calling this method, or
+    /// use [`scan_chunks_preserving`](SparseFile::scan_chunks_preserving).
     ///
     /// # Errors
     ///
@@ -158,93 +809,2752 @@ pub trait SparseFile: Read + Seek {
     /// Will also return `Err` if any other I/O error occurs
     fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError>;
 
+    /// [`scan_chunks`](SparseFile::scan_chunks) into `buf`, replacing what
+    /// was in it but reusing its capacity
+    ///
+    /// Saves an allocation per file when scanning many files in a loop. If
+    /// the scan fails the contents of `buf` are unspecified.
+    fn scan_chunks_into(&mut self, buf: &mut Vec<Segment>) -> Result<(), ScanError> {
+        buf.clear();
+        buf.extend(self.scan_chunks()?);
+        Ok(())
+    }
+
+    /// [`scan_chunks`](SparseFile::scan_chunks), but the seek position of
+    /// the file is left where it was, even if the scan fails
+    fn scan_chunks_preserving(&mut self) -> Result<Vec<Segment>, ScanError> {
+        let position = self.stream_position()?;
+        let segments = self.scan_chunks();
+        self.seek(SeekFrom::Start(position))?;
+        segments
+    }
+
+    /// Cheaply checks whether the file is sparse, without scanning it
+    ///
+    /// On unix the file is sparse if it has fewer blocks allocated than its
+    /// size needs (`st_blocks * 512 < st_size`). On Windows it is whether the
+    /// file is marked as sparse (`FILE_ATTRIBUTE_SPARSE_FILE`), which it can
+    /// be without actually having any holes. Other platforms return
+    /// `Err(ScanError::UnsupportedPlatform)`.
+    fn is_sparse(&self) -> Result<bool, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Marks the file as sparse, which is needed before it can have holes on
+    /// Windows (`FSCTL_SET_SPARSE`)
+    ///
+    /// Elsewhere every file can have holes, so this does nothing.
+    fn set_sparse(&self) -> Result<(), ScanError> {
+        Ok(())
+    }
+
     /// Unallocate a section of the file, freeing the disk space and making
     /// future reads return zeros
+    ///
+    /// On macOS only the part of the section aligned to the filesystem's
+    /// block size can be freed, any data in the unaligned edges is
+    /// overwritten with zeros instead.
+    ///
+    /// Returns `Err(ScanError::InvalidRange)` without touching the file if
+    /// `end` is before `start`.
     fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError>;
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_utils::*;
-    use quickcheck_macros::quickcheck;
-    use std::fs::File;
+    /// Finds the start of the first data at or after `offset`, like
+    /// `lseek(SEEK_DATA)`
+    ///
+    /// Returns `None` if there is no data at or after `offset`.
+    ///
+    /// Moves the seek position of the file.
+    fn seek_data(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        let _ = offset;
+        Err(ScanError::UnsupportedPlatform)
+    }
 
-    fn test_chunks_match(file: &mut File, input_segments: &[Segment]) -> bool {
-        // Get both sets of segments
-        let output_segments = file.scan_chunks().expect("Unable to scan chunks");
+    /// Finds the start of the first hole at or after `offset`, like
+    /// `lseek(SEEK_HOLE)`
+    ///
+    /// The end of the file counts as a hole, so this only returns `None` if
+    /// `offset` is at or beyond the end of the file.
+    ///
+    /// Moves the seek position of the file.
+    fn seek_hole(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        let _ = offset;
+        Err(ScanError::UnsupportedPlatform)
+    }
 
-        if *input_segments != output_segments {
-            println!("Expected: \n {:?} \n", input_segments);
-            println!("Got: \n {:?} \n", output_segments);
+    /// Returns true if the byte at the current seek position is part of a
+    /// hole
+    ///
+    /// Returns false at or beyond the end of the file. The seek position is
+    /// left where it was.
+    fn current_in_hole(&mut self) -> Result<bool, ScanError> {
+        let position = self.stream_position()?;
+        let hole = self.seek_hole(position);
+        self.seek(SeekFrom::Start(position))?;
+        Ok(hole? == Some(position))
+    }
+
+    /// Where [`scan_chunks_iter`](SparseFile::scan_chunks_iter) stops
+    ///
+    /// On unix this also checks the file can be scanned at all, failing with
+    /// `Err(ScanError::NotARegularFile)` for directories and the like, and
+    /// asks devices for their length if seeking to their end gives 0.
+    /// Elsewhere it is the offset of the end of the file.
+    #[doc(hidden)]
+    fn scan_end(&mut self) -> Result<u64, ScanError> {
+        Ok(self.seek(SeekFrom::End(0))?)
+    }
+
+    /// Scans the file lazily, finding each segment only when it is asked for
+    ///
+    /// This produces the same segments as
+    /// [`scan_chunks`](SparseFile::scan_chunks) without ever holding them all
+    /// in memory, and stopping early skips the rest of the work. It is built
+    /// on [`seek_hole`](SparseFile::seek_hole) and
+    /// [`seek_data`](SparseFile::seek_data), so on Windows each segment
+    /// queries the allocated ranges from its start. The iterator ends after
+    /// the first error.
+    ///
+    /// Like `scan_chunks` this does not preserve the seek position of the
+    /// file.
+    fn scan_chunks_iter(&mut self) -> ScanIter<'_, Self>
+    where
+        Self: Sized,
+    {
+        ScanIter {
+            file: self,
+            stepper: None,
+            deadline: None,
+            done: false,
         }
-        *input_segments == output_segments
     }
 
-    // Creates a file based on desc, then tests that the resulting output of
-    // file.scan_chunks() matches the description used to create the file
-    fn test_round_trips(desc: SparseDescription) -> bool {
-        let mut file = desc.to_file();
-        // Get both sets of segments
-        let input_segments = desc.segments();
-        test_chunks_match(file.as_file_mut(), &input_segments)
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), adding
+    /// the syscalls made, the length scanned, and the time taken to `stats`
+    ///
+    /// The syscalls made by a failed scan are still counted.
+    #[cfg(feature = "stats")]
+    fn scan_chunks_with_stats(&mut self, stats: &mut ScanStats) -> Result<Vec<Segment>, ScanError> {
+        let start = Instant::now();
+        let (mut scan, segments) = stats::counting(|| self.scan_chunks());
+        scan.scans = 1;
+        scan.elapsed = start.elapsed();
+        if let Ok(segments) = &segments {
+            scan.bytes_scanned = segments.last().map(|s| s.range.end).unwrap_or(0);
+        }
+        *stats += scan;
+        segments
     }
 
-    #[quickcheck]
-    fn round_trips(desc: SparseDescription) -> bool {
-        test_round_trips(desc)
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), calling
+    /// `on_segment` with each segment in order as it is found
+    ///
+    /// This lets progress through a long scan be reported, e.g. as the end of
+    /// the latest segment out of the length of the file. On unix each segment
+    /// is passed on as soon as `lseek` finds it, elsewhere they are only
+    /// passed on once the whole scan is done.
+    fn scan_chunks_with_progress(
+        &mut self,
+        mut on_segment: impl FnMut(&Segment),
+    ) -> Result<Vec<Segment>, ScanError>
+    where
+        Self: Sized,
+    {
+        let segments = self.scan_chunks()?;
+        segments.iter().for_each(&mut on_segment);
+        Ok(segments)
     }
 
-    #[quickcheck]
-    fn drill_hole(desc: SparseDescription, drop: u8) -> bool {
-        let mut file = desc.to_file();
-        // Get both sets of segments
-        let mut input_segments = desc.segments();
+    /// Scans the file from `offset` onwards, like
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    ///
+    /// The first segment returned starts at `offset`, and the segments cover
+    /// every byte from there to the end of the file. If `offset` is at or
+    /// beyond the end of the file no segments are returned.
+    fn scan_chunks_from(&mut self, offset: u64) -> Result<Vec<Segment>, ScanError> {
+        self.scan_chunks_range(offset..u64::MAX)
+    }
 
-        if input_segments.is_empty() {
-            return true;
+    /// Scans just the part of the file covered by `range`, like
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    ///
+    /// The segments cover every byte of `range` that is within the file, with
+    /// the first and last clamped to its ends. On unix and windows only the
+    /// part of the file in `range` is queried, so this is much cheaper than a
+    /// full scan when the range is small.
+    fn scan_chunks_range(&mut self, range: Range<u64>) -> Result<Vec<Segment>, ScanError> {
+        let mut segments = self.scan_chunks()?;
+        segments.retain(|s| s.range.end > range.start && s.range.start < range.end);
+        if let Some(first) = segments.first_mut() {
+            first.range.start = first.range.start.max(range.start);
         }
+        if let Some(last) = segments.last_mut() {
+            last.range.end = last.range.end.min(range.end);
+        }
+        Ok(segments)
+    }
 
-        #[cfg(target_os = "macos")]
-        for hole in input_segments.holes() {
-            file.as_file_mut()
-                .drill_hole(hole.start, hole.end)
-                .expect("pre drill holes");
+    /// Scans the file, calling `f` with each segment in order until
+    /// `deadline` passes
+    ///
+    /// The scan is lazy, like [`scan_chunks_iter`](SparseFile::scan_chunks_iter),
+    /// and the clock is checked before each seek and before each segment is
+    /// handed to `f`, so a slow scan is stopped part way through as well as a
+    /// slow `f`. If the deadline has passed,
+    /// `Err(ScanError::TimedOut { offset })` is returned, where `offset` is
+    /// the start of the first segment that was not processed. The scan can
+    /// then be resumed later from that point with
+    /// [`scan_chunks_from`](SparseFile::scan_chunks_from).
+    fn for_each_segment_until<F>(&mut self, deadline: Instant, mut f: F) -> Result<(), ScanError>
+    where
+        F: FnMut(&Segment),
+        Self: Sized,
+    {
+        let scan = ScanIter {
+            file: self,
+            stepper: None,
+            deadline: Some(deadline),
+            done: false,
+        };
+        for segment in scan {
+            let segment = segment?;
+            if Instant::now() >= deadline {
+                return Err(ScanError::TimedOut {
+                    offset: segment.start(),
+                });
+            }
+            f(&segment);
         }
+        Ok(())
+    }
 
-        test_chunks_match(file.as_file_mut(), &input_segments);
+    /// The preferred size for I/O transfers on this file, as recommended by
+    /// the OS
+    ///
+    /// This is `st_blksize` on unix, and a fixed 64KiB on Windows. It is not
+    /// necessarily the allocation unit of the filesystem (see
+    /// [`block_size`](SparseFile::block_size)), but is used as the buffer
+    /// size by the helpers in this crate that read file content.
+    fn io_block_size(&self) -> Result<u64, ScanError>;
 
-        // pick a segment to make a hole
-        let drop_idx = drop as usize % input_segments.len();
-        let drop = &mut input_segments[drop_idx];
+    /// The allocation unit of the filesystem holding this file, the smallest
+    /// region [`drill_hole`](SparseFile::drill_hole) can actually free
+    ///
+    /// This is the fragment size (`f_frsize`) from `fstatvfs` on unix, not
+    /// `st_blksize`, which is only a hint for efficient I/O. On Windows it is
+    /// the unit NTFS allocates
+    /// sparse files in, 16 clusters of the volume (as reported by
+    /// `GetDiskFreeSpace`), so 64KiB with the usual 4KiB clusters.
+    fn block_size(&self) -> Result<u64, ScanError>;
 
-        file.as_file_mut()
-            .drill_hole(drop.range.start, drop.range.end)
-            .expect("drilled hole");
+    /// Returns the granularity the file's holes are reported at, along with
+    /// the granularity they can be drilled at, as
+    /// `(scan_granularity, punch_granularity)`
+    ///
+    /// These can differ: a filesystem may report holes to the byte but only
+    /// free whole blocks. The first comes from
+    /// [`observed_granularity`](SparseFile::observed_granularity) and the
+    /// second from [`block_size`](SparseFile::block_size).
+    fn granularities(&mut self) -> Result<(u64, u64), ScanError> {
+        Ok((self.observed_granularity()?, self.block_size()?))
+    }
 
-        drop.segment_type = SegmentType::Hole;
+    /// Scans the file's extents, reporting how each segment is stored
+    ///
+    /// Unlike [`scan_chunks`](SparseFile::scan_chunks) this is built from the
+    /// extents the filesystem has allocated, so preallocated but unwritten
+    /// regions are reported as `Data` with the `unwritten` flag set. Adjacent
+    /// extents are merged when their flags match.
+    ///
+    /// Only supported on Linux (via `FS_IOC_FIEMAP`), other platforms return
+    /// `Err(ScanError::UnsupportedPlatform)`
+    fn scan_chunks_detailed(&mut self) -> Result<Vec<DetailedSegment>, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
 
-        combine_segments(&mut input_segments);
+    /// Grows the file by `additional` bytes, all of them a hole
+    ///
+    /// On unix this is the same as extending the file with `set_len`. On
+    /// Windows the file is marked as sparse first, as otherwise the new
+    /// bytes would be allocated, failing if the filesystem can't hold sparse
+    /// files. Other platforms return `Err(ScanError::UnsupportedPlatform)`.
+    fn extend_with_hole(&self, additional: u64) -> Result<(), ScanError> {
+        let _ = additional;
+        Err(ScanError::UnsupportedPlatform)
+    }
 
-        test_chunks_match(file.as_file_mut(), &input_segments)
+    /// Allocates disk space for `len` bytes from `start`, the inverse of
+    /// [`drill_hole`](SparseFile::drill_hole)
+    ///
+    /// Reserving the space for a region before writing it lets the
+    /// filesystem lay it out contiguously. Any part of the region that was a
+    /// hole reads as zeros afterwards, and existing data is left as it is.
+    /// The file is extended if the region runs past its end, and a `len` of
+    /// 0 does nothing.
+    ///
+    /// Only supported where `fallocate` is (Linux, Android and FreeBSD),
+    /// other platforms return `Err(ScanError::UnsupportedPlatform)`. Returns
+    /// `Err(ScanError::OutOfSpace)` if there is not enough free space.
+    fn reserve(&self, start: u64, len: u64) -> Result<(), ScanError> {
+        let _ = (start, len);
+        Err(ScanError::UnsupportedPlatform)
     }
-    #[quickcheck]
-    fn one_big_segment(segment_type: SegmentType) -> bool {
-        let desc = SparseDescription::one_segment(segment_type, 3545868);
 
-        test_round_trips(desc)
+    /// Makes `dst` a copy-on-write clone of the whole file, sharing its
+    /// extents rather than copying any data, so the layout is preserved
+    /// exactly
+    ///
+    /// Only supported on Linux (via the `FICLONE` ioctl), other platforms
+    /// return `Err(ScanError::UnsupportedPlatform)`. Filesystems without
+    /// reflinks, or a `dst` on a different filesystem, give
+    /// `Err(ScanError::UnsupportedFileSystem)`.
+    fn reflink_to(&self, dst: &File) -> Result<(), ScanError> {
+        let _ = dst;
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Lists the extents the filesystem has allocated for the file, along with
+    /// where each one physically lives on disk, in logical order
+    ///
+    /// Extents are reported exactly as the filesystem gives them, so
+    /// neighbouring ones are not merged and the last may extend beyond the
+    /// end of the file if space was preallocated.
+    ///
+    /// Only supported on Linux (via `FS_IOC_FIEMAP`), other platforms return
+    /// `Err(ScanError::UnsupportedPlatform)`
+    fn physical_extents(&mut self) -> Result<Vec<PhysicalExtent>, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), but
+    /// cross-checks the result against the space the file actually occupies
+    /// on disk
+    ///
+    /// Some FUSE and overlay filesystems answer `SEEK_DATA`/`SEEK_HOLE` with
+    /// plausible looking but wrong values, typically reporting the whole file
+    /// as data. If the reported data wildly exceeds the physical allocation a
+    /// warning is logged (with the `log` feature enabled), and if
+    /// `content_fallback` is set the layout is instead rebuilt by reading the
+    /// file and treating runs of zero blocks as holes.
+    ///
+    /// Filesystems that compress data can also trip this check, in which case
+    /// the fallback is merely slower, not wrong.
+    ///
+    /// On platforms without a way to query the physical allocation this is
+    /// the same as `scan_chunks`.
+    fn scan_chunks_robust(&mut self, content_fallback: bool) -> Result<Vec<Segment>, ScanError> {
+        let _ = content_fallback;
+        self.scan_chunks()
+    }
+
+    /// [`scan_chunks`](SparseFile::scan_chunks), checking that the segments
+    /// really do cover all bytes of the file
+    ///
+    /// Each segment must start where the previous one ended, the first at 0
+    /// and the last ending at the length of the file.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::NonContiguous)` with the first range that
+    /// breaks this, as well as any error from `scan_chunks`.
+    fn scan_chunks_checked(&mut self) -> Result<Vec<Segment>, ScanError> {
+        let segments = self.scan_chunks()?;
+        let len = self.seek(SeekFrom::End(0))?;
+        check_contiguous(&segments, len)?;
+        Ok(segments)
+    }
+
+    /// [`scan_chunks`](SparseFile::scan_chunks) with the given `opts`
+    ///
+    /// With `detect_zeros_in_nonsparse` set, a file that
+    /// [`is_sparse`](SparseFile::is_sparse) says is not sparse is read in
+    /// blocks of [`io_block_size`](SparseFile::io_block_size), and each
+    /// block that is entirely zero is reported as a hole. Does not preserve
+    /// the seek position of the file in that case.
+    fn scan_chunks_opts(&mut self, opts: ScanOptions) -> Result<Vec<Segment>, ScanError> {
+        let segments = self.scan_chunks()?;
+        if !opts.detect_zeros_in_nonsparse || self.is_sparse()? {
+            return Ok(segments);
+        }
+        let block_size = self.io_block_size()?;
+        find_zero_blocks(self, &segments, block_size)
+    }
+
+    /// Feeds the logical content of the file to `hasher`, reading only its
+    /// `Data` segments
+    ///
+    /// Zeros are hashed the same whether they were read or are in a hole,
+    /// so two files with the same content hash the same however sparse they
+    /// are. The bytes fed are an encoding of the content rather than the
+    /// content itself, so the hash differs from hashing the raw bytes. Does
+    /// not preserve the seek position of the file.
+    fn hash_data<H: std::hash::Hasher>(&mut self, hasher: &mut H) -> Result<(), ScanError>
+    where
+        Self: Sized,
+    {
+        hash::hash_data(self, hasher)
+    }
+
+    /// Returns true if any part of `start..end` contains data, and so
+    /// drilling a hole there would have an effect
+    ///
+    /// Idempotent sparsifiers can use this to skip the syscall for ranges
+    /// that are already entirely holes. Does not preserve the seek position of
+    /// the file.
+    fn needs_punch(&mut self, start: u64, end: u64) -> Result<bool, ScanError> {
+        if start >= end {
+            return Ok(false);
+        }
+        Ok(self
+            .scan_chunks_range(start..end)?
+            .iter()
+            .any(Segment::is_data))
     }
 
-    fn combine_segments(segments: &mut Vec<Segment>) {
-        let mut prev = 0;
-        for i in 1..segments.len() {
-            if segments[prev].segment_type == segments[i].segment_type {
-                segments[prev].range.end = segments[i].range.end;
-            } else {
-                prev += 1;
-                segments[prev] = segments[i].clone();
+    /// Drills a hole from `start` to `end` like
+    /// [`drill_hole`](SparseFile::drill_hole), then scans the range again
+    /// and returns how many bytes of it are now holes
+    ///
+    /// Filesystems only free whole blocks, zeroing any partial blocks at the
+    /// edges instead, so this can be less than `end - start`, or even 0 for a
+    /// small range. Does not preserve the seek position of the file.
+    fn drill_hole_verified(&mut self, start: u64, end: u64) -> Result<u64, ScanError> {
+        self.drill_hole(start, end)?;
+        Ok(self.scan_chunks_range(start..end)?.hole_len())
+    }
+
+    /// Drills each of `ranges` in turn, stopping at the first that fails
+    ///
+    /// Empty ranges are skipped, but backwards ones fail. If a range fails
+    /// `Err(ScanError::DrillFailed)` is returned with its index, so that the
+    /// rest can be retried from there.
+    fn drill_holes(&self, ranges: &[Range<u64>]) -> Result<(), ScanError> {
+        for (index, range) in ranges.iter().enumerate() {
+            if range.start == range.end {
+                continue;
             }
+            self.drill_hole(range.start, range.end)
+                .map_err(|source| ScanError::DrillFailed {
+                    index,
+                    range: range.clone(),
+                    source: Box::new(source),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Unallocate the part of a section of the file that is aligned to
+    /// `granularity`, leaving the unaligned edges untouched
+    ///
+    /// The start of the range is rounded up and the end rounded down to
+    /// multiples of `granularity`, so that only whole aligned regions are
+    /// ever freed. Returns the range that was actually drilled, which will be
+    /// empty if no aligned region fits inside `start..end`. Returns
+    /// `Err(ScanError::ZeroBlockSize)` without touching the file if
+    /// `granularity` is zero.
+    fn drill_hole_granularity(
+        &self,
+        start: u64,
+        end: u64,
+        granularity: u64,
+    ) -> Result<Range<u64>, ScanError> {
+        if granularity == 0 {
+            return Err(ScanError::ZeroBlockSize);
         }
-        segments.truncate(prev + 1)
+        let aligned = align_inward(start..end, granularity);
+        if !aligned.is_empty() {
+            self.drill_hole(aligned.start, aligned.end)?;
+        }
+        Ok(aligned)
+    }
+
+    /// Scans the file, drilling every `Hole` segment as it goes so that the
+    /// reported holes are guaranteed to be physically freed
+    ///
+    /// Returns the layout along with the total number of bytes drilled. Some
+    /// of these bytes may already have been unallocated, so this is an upper
+    /// bound on the space actually reclaimed.
+    ///
+    /// Like [`scan_chunks`](SparseFile::scan_chunks) this does not preserve
+    /// the seek position of the file.
+    fn scan_and_repunch(&mut self) -> Result<(Vec<Segment>, u64), ScanError> {
+        let segments = self.scan_chunks()?;
+        let mut drilled = 0;
+        for hole in segments.iter().filter(|s| s.is_hole()) {
+            self.drill_hole(hole.range.start, hole.range.end)?;
+            drilled += hole.len();
+        }
+        Ok((segments, drilled))
+    }
+
+    /// Drills every `Hole` segment of the file, returning the total number of
+    /// bytes drilled
+    ///
+    /// Like [`scan_and_repunch`](SparseFile::scan_and_repunch), but the seek
+    /// position of the file is left where it was.
+    fn drill_all_holes(&mut self) -> Result<u64, ScanError> {
+        let position = self.stream_position()?;
+        let drilled = self.scan_and_repunch().map(|(_, drilled)| drilled);
+        self.seek(SeekFrom::Start(position))?;
+        drilled
+    }
+
+    /// Copies the file into `dst`, writing only the `Data` segments so the
+    /// holes are left for `dst` to fill however it does by default
+    ///
+    /// `dst` is set to the same length as the file at the end. Returns the
+    /// number of bytes of data copied. Does not preserve the seek position of
+    /// either file.
+    fn copy_to<W>(&mut self, dst: &mut W) -> Result<u64, ScanError>
+    where
+        Self: Sized,
+        W: Write + Seek + SetLen + ?Sized,
+    {
+        let segments = self.scan_chunks()?;
+        let buffer_size = self.io_block_size()?;
+        let copied = copy_data(self, dst, &segments, buffer_size)?;
+        dst.set_len(segments.total_len())?;
+        Ok(copied)
+    }
+
+    /// Copies just the part of the file in `range` into `dst`, like
+    /// [`copy_to`](SparseFile::copy_to), for replicating only the regions
+    /// that changed
+    ///
+    /// Only `range` is scanned, and its `Data` segments are written to the
+    /// same offsets in `dst`, leaving the rest of `dst` untouched, including
+    /// where `range` has holes. The range is clamped to the end of the file.
+    /// Returns the number of bytes of data copied. Does not preserve the seek
+    /// position of either file.
+    fn copy_range_to<W>(&mut self, range: Range<u64>, dst: &mut W) -> Result<u64, ScanError>
+    where
+        Self: Sized,
+        W: Write + Seek + ?Sized,
+    {
+        let segments = self.scan_chunks_range(range)?;
+        let buffer_size = self.io_block_size()?;
+        Ok(copy_data(self, dst, &segments, buffer_size)?)
+    }
+
+    /// Shrinks the file to end at its last byte of data, removing any hole at
+    /// the end, and returns the new length
+    ///
+    /// A file that ends in data is left as it is. Does not preserve the seek
+    /// position of the file.
+    fn truncate_trailing_hole(&mut self) -> Result<u64, ScanError>
+    where
+        Self: SetLen + Sized,
+    {
+        let segments = self.scan_chunks()?;
+        match segments.last() {
+            Some(last) if last.is_hole() => {
+                self.set_len(last.start())?;
+                Ok(last.start())
+            }
+            _ => Ok(segments.total_len()),
+        }
+    }
+
+    /// Writes zeros over every `Hole` segment of the file, so that all of it
+    /// is allocated on disk, and returns the number of bytes written
+    ///
+    /// The inverse of [`drill_all_holes`](SparseFile::drill_all_holes). The
+    /// length and contents of the file are unchanged, and the seek position is
+    /// left where it was. Returns `Err(ScanError::OutOfSpace)` if the device
+    /// fills up part way through.
+    fn fill_holes(&mut self) -> Result<u64, ScanError>
+    where
+        Self: Write + Sized,
+    {
+        static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+        let position = self.stream_position()?;
+        let mut filled = 0;
+        let result = self.scan_chunks().and_then(|segments| {
+            for hole in segments.holes() {
+                self.seek(SeekFrom::Start(hole.start))?;
+                let mut remaining = hole.end - hole.start;
+                while remaining > 0 {
+                    let len = remaining.min(ZEROS.len() as u64);
+                    self.write_all(&ZEROS[..len as usize])
+                        .map_err(allocation_error)?;
+                    remaining -= len;
+                    filled += len;
+                }
+            }
+            Ok(())
+        });
+        self.seek(SeekFrom::Start(position))?;
+        result.map(|()| filled)
+    }
+
+    /// Drills holes over every part of the file not covered by `data_ranges`,
+    /// without reading any of it
+    ///
+    /// This is for reclaiming space after writing a file out of order, when
+    /// the regions that have not been received yet are known to be zero
+    /// filled placeholders. The gaps between the ranges are shrunk to
+    /// multiples of [`block_size`](SparseFile::block_size) before being
+    /// drilled, so partially covered blocks are always kept. The ranges may be
+    /// given in any order and may overlap.
+    ///
+    /// Returns the number of bytes drilled.
+    fn sparsify_except(&mut self, data_ranges: &[Range<u64>]) -> Result<u64, ScanError> {
+        let len = self.seek(SeekFrom::End(0))?;
+        let block_size = self.block_size()?;
+
+        let mut data_ranges = data_ranges.to_vec();
+        data_ranges.sort_unstable_by_key(|r| r.start);
+        data_ranges.push(len..len);
+
+        let mut drilled = 0;
+        let mut gap_start = 0;
+        for range in data_ranges {
+            let gap_end = range.start.min(len);
+            if gap_start < gap_end {
+                let hole = self.drill_hole_granularity(gap_start, gap_end, block_size)?;
+                drilled += hole.end - hole.start;
+            }
+            gap_start = gap_start.max(range.end);
+        }
+        Ok(drilled)
+    }
+
+    /// Scans the file and returns the ranges of its holes, for drilling at the
+    /// caller's own pace
+    ///
+    /// The iterator owns the scan results rather than borrowing the file, so
+    /// [`drill_hole`](SparseFile::drill_hole) can be called on each range as
+    /// it is produced, with whatever pauses between them the caller needs.
+    fn holes_to_punch(&mut self) -> Result<HoleRanges, ScanError> {
+        Ok(HoleRanges {
+            iter: self.scan_chunks()?.into_iter(),
+        })
+    }
+
+    /// Returns both the layout reported by the OS and a zero-verified version
+    /// of it, in a single pass
+    ///
+    /// The first list is the output of [`scan_chunks`](SparseFile::scan_chunks).
+    /// The second is built by reading each `Data` segment once, in
+    /// `block_size` blocks aligned to the start of the file, and reporting
+    /// any block that contains only zeros as a hole.
+    ///
+    /// Like `scan_chunks` this does not preserve the seek position of the
+    /// file. Returns `Err(ScanError::ZeroBlockSize)` if `block_size` is zero.
+    fn scan_both(&mut self, block_size: u64) -> Result<(Vec<Segment>, Vec<Segment>), ScanError> {
+        let raw = self.scan_chunks()?;
+        let verified = find_zero_blocks(self, &raw, block_size)?;
+        Ok((raw, verified))
+    }
+
+    /// Drills holes over any blocks of zeros in the `Data` segments of the
+    /// file, reclaiming space that was written as explicit zeros
+    ///
+    /// Each `Data` segment is read in `block_size` blocks aligned to the start
+    /// of the file, as in [`scan_both`](SparseFile::scan_both), and runs of
+    /// blocks containing only zeros are drilled together. The partial block
+    /// at the end of a segment is checked too. Blocks containing any non-zero
+    /// byte are never drilled.
+    ///
+    /// Returns the number of bytes the filesystem reports as data before but
+    /// not after, which can be less than the zeros found when they do not
+    /// cover whole filesystem blocks. Does not preserve the seek position of
+    /// the file.
+    ///
+    /// On Windows the file is marked sparse first if there is anything to
+    /// drill, as `FSCTL_SET_ZERO_DATA` only writes zeros to other files.
+    ///
+    /// Returns `Err(ScanError::ZeroBlockSize)` without touching the file if
+    /// `block_size` is zero.
+    fn punch_zeros(&mut self, block_size: u64) -> Result<u64, ScanError> {
+        punch_blocks(self, block_size, &is_zero)
+    }
+
+    /// Drills holes over any blocks in the `Data` segments of the file for
+    /// which `predicate` returns true, whatever they contain
+    ///
+    /// The general form of [`punch_zeros`](SparseFile::punch_zeros), which is
+    /// this with a predicate matching blocks of only zeros. `predicate` is
+    /// given each block as in [`scan_both`](SparseFile::scan_both), including
+    /// the partial block at the end of a segment, and the blocks it matches
+    /// read as zeros afterwards. Returns the number of bytes the filesystem
+    /// reports as data before but not after. Does not preserve the seek
+    /// position of the file. Returns `Err(ScanError::ZeroBlockSize)` without
+    /// touching the file if `block_size` is zero.
+    fn punch_if(
+        &mut self,
+        block_size: u64,
+        predicate: impl Fn(&[u8]) -> bool,
+    ) -> Result<u64, ScanError>
+    where
+        Self: Sized,
+    {
+        punch_blocks(self, block_size, &predicate)
+    }
+
+    /// Computes a 64-bit signature of the file's sparse structure, ignoring
+    /// its content
+    ///
+    /// The signature is a hash of the type of the first segment and the
+    /// lengths of every segment, so files with identical layouts share a
+    /// signature regardless of the data they contain. The hash (64-bit
+    /// FNV-1a) is stable across runs and platforms, so signatures can be
+    /// persisted in a cache.
+    fn layout_signature(&mut self) -> Result<u64, ScanError> {
+        Ok(signature(&self.scan_chunks()?))
+    }
+
+    /// Scans the file, then absorbs every segment shorter than `min` bytes
+    /// into its neighbours, so downstream code can tile the file without
+    /// dealing with tiny segments
+    ///
+    /// The shortest segment is always absorbed first, the earliest one
+    /// winning ties, until every segment is at least `min` bytes long or only
+    /// one segment remains. A short segment is reclassified as the type of
+    /// the neighbour it merges with: the neighbours of a segment always share
+    /// a type (segments alternate), so the short segment and both neighbours
+    /// become one. At either end of the file it takes the type of its only
+    /// neighbour. Should the neighbours ever differ, the larger one wins, the
+    /// preceding one on a tie.
+    ///
+    /// The output still covers every byte of the file, but as short data
+    /// segments can be absorbed into holes, `Hole` segments are no longer
+    /// guaranteed to contain only zeros.
+    fn scan_chunks_min_segment(&mut self, min: u64) -> Result<Vec<Segment>, ScanError> {
+        Ok(absorb_short_segments(&self.scan_chunks()?, min))
+    }
+
+    /// Returns true if the [`layout_signature`](SparseFile::layout_signature)
+    /// of the file differs from the previously computed `prev`
+    fn layout_changed_since(&mut self, prev: u64) -> Result<bool, ScanError> {
+        Ok(self.layout_signature()? != prev)
+    }
+
+    /// Scans the file and returns the greatest common divisor of every
+    /// segment's start offset and length
+    ///
+    /// This reveals the effective granularity the filesystem tracks holes at,
+    /// which is often the block size but can be larger, and explains why
+    /// drilling holes smaller than it has no visible effect. Note that the
+    /// length of the file counts too, so a file whose length is not a
+    /// multiple of the block size will report a smaller granularity. Returns
+    /// 0 for an empty file.
+    fn observed_granularity(&mut self) -> Result<u64, ScanError> {
+        Ok(granularity(&self.scan_chunks()?))
+    }
+
+    /// Condenses how fragmented the file's data is into a single number
+    ///
+    /// The score is
+    /// `data_segments as f64 / (1 + data_len / io_block_size) as f64`, where
+    /// `data_segments` is the number of `Data` segments, `data_len` their
+    /// total length in bytes and `io_block_size` comes from
+    /// [`io_block_size`](SparseFile::io_block_size), with integer division.
+    /// A file whose data is one contiguous run scores close to 0, while one
+    /// whose data is split into a run per block scores close to 1. Empty
+    /// files and files with no data score 0.
+    fn fragmentation_score(&mut self) -> Result<f64, ScanError> {
+        let segments = self.scan_chunks()?;
+        Ok(fragmentation(&segments, self.io_block_size()?))
+    }
+
+    /// The number of bytes the file actually occupies on disk
+    ///
+    /// On unix this comes from `st_blocks`, so includes any blocks the
+    /// filesystem allocated beyond the data, such as preallocated space.
+    /// Elsewhere it is the total length of the `Data` segments.
+    fn sparse_len(&mut self) -> Result<u64, ScanError> {
+        Ok(self.scan_chunks()?.data_len())
+    }
+
+    /// The apparent size of the file, the end of the last segment of its
+    /// scan
+    fn logical_len(&mut self) -> Result<u64, ScanError> {
+        Ok(self.scan_chunks()?.total_len())
+    }
+
+    /// The total length of the `Data` segments of the file
+    ///
+    /// Unlike [`sparse_len`](SparseFile::sparse_len) this always comes from
+    /// the scan, so does not include space allocated beyond the data.
+    fn allocated_len(&mut self) -> Result<u64, ScanError> {
+        Ok(self.scan_chunks()?.data_len())
+    }
+
+    /// The fraction of the file that is holes, from 0.0 for a file with no
+    /// holes to 1.0 for one that is entirely hole
+    ///
+    /// Empty files have a ratio of 0.0.
+    fn sparse_ratio(&mut self) -> Result<f64, ScanError> {
+        let segments = self.scan_chunks()?;
+        match segments.total_len() {
+            0 => Ok(0.0),
+            len => Ok(segments.hole_len() as f64 / len as f64),
+        }
+    }
+
+    /// How many bytes less the file occupies on disk than it did when
+    /// [`sparse_len`](SparseFile::sparse_len) returned `baseline_allocated`
+    ///
+    /// Positive when space has been freed, negative when more was allocated.
+    fn reclaimed_since(&mut self, baseline_allocated: u64) -> Result<i64, ScanError> {
+        Ok(baseline_allocated as i64 - self.sparse_len()? as i64)
+    }
+
+    /// Gathers the common metrics about the file's sparseness into one
+    /// [`Report`], e.g. for structured logging
+    fn report(&mut self) -> Result<Report, ScanError> {
+        let segments = self.scan_chunks()?;
+        Ok(Report {
+            logical_len: segments.last().map(|s| s.range.end).unwrap_or(0),
+            data_len: segments.data_len(),
+            hole_len: segments.hole_len(),
+            allocated_len: self.sparse_len()?,
+            segment_count: segments.len(),
+            fragmentation_score: fragmentation(&segments, self.io_block_size()?),
+            block_size: self.block_size()?,
+        })
+    }
+
+    /// Scans the file and returns just the offsets where it changes between
+    /// holes and data, in ascending order
+    ///
+    /// Neither 0 nor the length of the file are included. Together with the
+    /// type of the first segment and the length of the file, this is the most
+    /// compact description of the layout.
+    fn boundaries(&mut self) -> Result<Vec<u64>, ScanError> {
+        let segments = coalesced(self.scan_chunks()?);
+        Ok(segments.iter().skip(1).map(Segment::start).collect())
+    }
+
+    /// Scans the file and checks its layout is the same as `expected`
+    ///
+    /// Neighbouring segments of the same type are merged on both sides before
+    /// comparing, so it does not matter how the layout was split up.
+    fn matches_layout(&mut self, expected: &[Segment]) -> Result<bool, ScanError> {
+        Ok(self.verify_layout(expected)?.is_empty())
+    }
+
+    /// Scans the file and returns the ranges where its layout differs from
+    /// `expected`, typed as they actually are in the file
+    ///
+    /// If the two layouts are of different lengths, the part past the end of
+    /// the shorter one is always reported, with anything past the end of the
+    /// file reported as a hole. An empty result means the layouts match.
+    fn verify_layout(&mut self, expected: &[Segment]) -> Result<Vec<Segment>, ScanError> {
+        let actual = self.scan_chunks()?;
+        Ok(layout_diff(&actual, expected))
+    }
+
+    /// Captures the sparse structure of the file as a [`Layout`]
+    ///
+    /// Like [`scan_chunks`](SparseFile::scan_chunks) this does not preserve
+    /// the seek position of the file.
+    fn to_layout(&mut self) -> Result<Layout, ScanError> {
+        Ok(self.scan_chunks()?.into())
+    }
+
+    /// Consuming version of [`to_layout`](SparseFile::to_layout), dropping the
+    /// file as soon as its structure has been captured
+    fn into_layout(mut self) -> Result<Layout, ScanError>
+    where
+        Self: Sized,
+    {
+        self.to_layout()
+    }
+}
+
+/// See [`scan_chunks_min_segment`](SparseFile::scan_chunks_min_segment)
+#[cfg(feature = "std")]
+fn absorb_short_segments(segments: &[Segment], min: u64) -> Vec<Segment> {
+    let mut segments = coalesced(segments.iter().cloned());
+
+    while segments.len() > 1 {
+        let short = segments
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.len() < min)
+            .min_by_key(|(_, s)| s.len());
+        let index = match short {
+            Some((index, _)) => index,
+            None => break,
+        };
+
+        let prev = index.checked_sub(1).map(|i| &segments[i]);
+        let next = segments.get(index + 1);
+        let neighbour = match (prev, next) {
+            (Some(prev), Some(next)) if next.len() > prev.len() => next,
+            (Some(prev), _) => prev,
+            (None, Some(next)) => next,
+            (None, None) => unreachable!("there is more than one segment"),
+        };
+
+        segments[index].segment_type = neighbour.segment_type;
+        segments = coalesced(segments);
+    }
+
+    segments
+}
+
+/// See [`scan_chunks_checked`](SparseFile::scan_chunks_checked)
+#[cfg(feature = "std")]
+fn check_contiguous(segments: &[Segment], len: u64) -> Result<(), ScanError> {
+    let mut expected = 0;
+    for segment in segments.iter().map(|s| &s.range).chain([&(len..len)]) {
+        if segment.start != expected {
+            let gap = expected.min(segment.start)..expected.max(segment.start);
+            return Err(ScanError::NonContiguous { gap });
+        }
+        expected = segment.end;
+    }
+    Ok(())
+}
+
+/// See [`verify_layout`](SparseFile::verify_layout)
+#[cfg(feature = "std")]
+fn layout_diff(actual: &[Segment], expected: &[Segment]) -> Vec<Segment> {
+    let mut boundaries: Vec<u64> = actual
+        .iter()
+        .chain(expected)
+        .flat_map(|s| [s.range.start, s.range.end])
+        .chain([0])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    fn type_at(layout: &[Segment], cursor: &mut usize, offset: u64) -> Option<SegmentType> {
+        while layout.get(*cursor).is_some_and(|s| s.range.end <= offset) {
+            *cursor += 1;
+        }
+        layout.get(*cursor).map(|s| s.segment_type)
+    }
+
+    let (mut actual_cursor, mut expected_cursor) = (0, 0);
+    let mut diff = Vec::new();
+    for range in boundaries.windows(2) {
+        let actual_type = type_at(actual, &mut actual_cursor, range[0]);
+        let expected_type = type_at(expected, &mut expected_cursor, range[0]);
+        if actual_type.is_none() || actual_type != expected_type {
+            let segment_type = actual_type.unwrap_or(SegmentType::Hole);
+            push_segment(&mut diff, segment_type, range[0]..range[1]);
+        }
+    }
+    diff
+}
+
+/// See [`fragmentation_score`](SparseFile::fragmentation_score)
+#[cfg(feature = "std")]
+fn fragmentation(segments: &[Segment], io_block_size: u64) -> f64 {
+    let data = segments.iter().filter(|s| s.is_data());
+    let data_segments = data.clone().count();
+    let data_len: u64 = data.map(Segment::len).sum();
+    data_segments as f64 / (1 + data_len / io_block_size) as f64
+}
+
+/// See [`observed_granularity`](SparseFile::observed_granularity)
+#[cfg(feature = "std")]
+fn granularity(segments: &[Segment]) -> u64 {
+    fn gcd(a: u64, b: u64) -> u64 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    segments
+        .iter()
+        .flat_map(|s| [s.start(), s.len()])
+        .fold(0, gcd)
+}
+
+/// Shrinks a range to the largest sub-range whose ends are multiples of
+/// `granularity`, returning an empty range if there is none
+#[cfg(feature = "std")]
+fn align_inward(range: Range<u64>, granularity: u64) -> Range<u64> {
+    let start = range.start.div_ceil(granularity) * granularity;
+    let end = range.end / granularity * granularity;
+    start..end.max(start)
+}
+
+/// FNV-1a hash of the run list of a layout, see
+/// [`layout_signature`](SparseFile::layout_signature)
+#[cfg(feature = "std")]
+fn signature(segments: &[Segment]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let start_type = segments.first().map(|s| match s.segment_type {
+        SegmentType::Hole => 1_u8,
+        SegmentType::Data => 2_u8,
+    });
+    let lengths = segments.iter().flat_map(|s| s.len().to_le_bytes());
+
+    start_type
+        .into_iter()
+        .chain(lengths)
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+/// See [`punch_if`](SparseFile::punch_if)
+#[cfg(feature = "std")]
+fn punch_blocks<F: SparseFile + ?Sized>(
+    file: &mut F,
+    block_size: u64,
+    predicate: &dyn Fn(&[u8]) -> bool,
+) -> Result<u64, ScanError> {
+    let raw = file.scan_chunks()?;
+    let verified = find_blocks_where(file, &raw, block_size, predicate)?;
+    if raw.data_len() == verified.data_len() {
+        return Ok(0);
+    }
+
+    file.set_sparse()?;
+    for hole in verified.holes() {
+        file.drill_hole(hole.start, hole.end)?;
+    }
+    Ok(raw.data_len().saturating_sub(file.allocated_len()?))
+}
+
+#[cfg(feature = "std")]
+fn is_zero(block: &[u8]) -> bool {
+    block.iter().all(|&b| b == 0)
+}
+
+/// Reads back the `Data` segments of `segments` in `block_size` blocks
+/// (aligned to the start of the file), reclassifying any block that contains
+/// only zeros as a hole
+#[cfg(feature = "std")]
+fn find_zero_blocks<F: Read + Seek + ?Sized>(
+    file: &mut F,
+    segments: &[Segment],
+    block_size: u64,
+) -> Result<Vec<Segment>, ScanError> {
+    find_blocks_where(file, segments, block_size, &is_zero)
+}
+
+/// Like [`find_zero_blocks`], but reclassifying the blocks matching
+/// `is_hole` as holes
+#[cfg(feature = "std")]
+fn find_blocks_where<F: Read + Seek + ?Sized>(
+    file: &mut F,
+    segments: &[Segment],
+    block_size: u64,
+    is_hole: &dyn Fn(&[u8]) -> bool,
+) -> Result<Vec<Segment>, ScanError> {
+    if block_size == 0 {
+        return Err(ScanError::ZeroBlockSize);
+    }
+    let mut output = Vec::with_capacity(segments.len());
+    let mut buffer = vec![0_u8; block_size as usize];
+
+    for segment in segments {
+        if segment.is_hole() {
+            push_segment(&mut output, SegmentType::Hole, segment.range.clone());
+            continue;
+        }
+
+        file.seek(SeekFrom::Start(segment.start()))?;
+        let mut offset = segment.start();
+        while offset < segment.range.end {
+            let block_end = ((offset / block_size + 1) * block_size).min(segment.range.end);
+            let block = &mut buffer[..(block_end - offset) as usize];
+            file.read_exact(block)?;
+
+            let segment_type = SegmentType::from_data(!is_hole(block));
+            push_segment(&mut output, segment_type, offset..block_end);
+            offset = block_end;
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use quickcheck_macros::quickcheck;
+
+    fn test_chunks_match(file: &mut File, input_segments: &[Segment]) -> bool {
+        // Get both sets of segments
+        let output_segments = file.scan_chunks().expect("Unable to scan chunks");
+
+        if *input_segments != output_segments {
+            println!("Expected: \n {:?} \n", input_segments);
+            println!("Got: \n {:?} \n", output_segments);
+        }
+        *input_segments == output_segments
+    }
+
+    // Creates a file based on desc, then tests that the resulting output of
+    // file.scan_chunks() matches the description used to create the file
+    fn test_round_trips(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        // Get both sets of segments
+        let input_segments = desc.segments();
+        test_chunks_match(file.as_file_mut(), &input_segments)
+    }
+
+    #[quickcheck]
+    fn round_trips(desc: SparseDescription) -> bool {
+        test_round_trips(desc)
+    }
+
+    #[quickcheck]
+    fn drill_hole(desc: SparseDescription, drop: u8) -> bool {
+        let mut file = desc.to_drilled_file();
+        // Get both sets of segments
+        let mut input_segments = desc.segments();
+
+        if input_segments.is_empty() {
+            return true;
+        }
+
+        test_chunks_match(file.as_file_mut(), &input_segments);
+
+        // pick a segment to make a hole
+        let drop_idx = drop as usize % input_segments.len();
+        let drop = input_segments[drop_idx].range.clone();
+
+        file.as_file_mut()
+            .drill_hole(drop.start, drop.end)
+            .expect("drilled hole");
+
+        apply_hole(&mut input_segments, drop.start, drop.end);
+
+        test_chunks_match(file.as_file_mut(), &input_segments)
+    }
+    #[quickcheck]
+    fn layouts_match(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let borrowed = file.as_file_mut().to_layout().expect("borrowed layout");
+        let owned = file
+            .reopen()
+            .expect("reopen file")
+            .into_layout()
+            .expect("owned layout");
+
+        borrowed == owned
+    }
+
+    #[quickcheck]
+    fn robust_scan_trusts_honest_filesystems(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let robust = file
+            .as_file_mut()
+            .scan_chunks_robust(true)
+            .expect("robust scan");
+
+        test_chunks_match(file.as_file_mut(), &robust)
+    }
+
+    #[test]
+    fn scan_both_splits_zero_data() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&[1; 4096]).expect("write data");
+        file.write_all(&[0; 8192]).expect("write zeros");
+        file.write_all(&[1; 4096]).expect("write data");
+
+        let (raw, verified) = file.scan_both(4096).expect("scan both");
+
+        assert_eq!(raw, vec![Segment::data(0..16384)]);
+        assert_eq!(
+            verified,
+            vec![
+                Segment::data(0..4096),
+                Segment::hole(4096..12288),
+                Segment::data(12288..16384),
+            ]
+        );
+    }
+
+    #[test]
+    fn with_prev_pairs_predecessors() {
+        let segments = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]).segments();
+        let pairs: Vec<_> = with_prev(&segments).collect();
+
+        assert_eq!(
+            pairs,
+            vec![
+                (None, &segments[0]),
+                (Some(SegmentType::Data), &segments[1]),
+                (Some(SegmentType::Hole), &segments[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn signature_ignores_content() {
+        use std::io::Write;
+
+        let layout = |fill: u8, len: u64| {
+            let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+            file.write_all(&[fill; 4096]).expect("write data");
+            file.set_len(len).expect("set length");
+            file.layout_signature().expect("signature")
+        };
+
+        assert_eq!(layout(1, 12288), layout(7, 12288));
+        assert_ne!(layout(1, 12288), layout(1, 8192));
+    }
+
+    #[test]
+    fn drill_hole_granularity_only_frees_aligned() {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, 8 * BLOCK_SIZE).to_file();
+        let file = file.as_file_mut();
+
+        let drilled = file
+            .drill_hole_granularity(BLOCK_SIZE + 100, 7 * BLOCK_SIZE - 100, 2 * BLOCK_SIZE)
+            .expect("drilled hole");
+        assert_eq!(drilled, 2 * BLOCK_SIZE..6 * BLOCK_SIZE);
+
+        let too_small = file
+            .drill_hole_granularity(BLOCK_SIZE, 3 * BLOCK_SIZE, 4 * BLOCK_SIZE)
+            .expect("drilled nothing");
+        assert!(too_small.is_empty());
+
+        assert!(matches!(
+            file.drill_hole_granularity(0, 8 * BLOCK_SIZE, 0),
+            Err(ScanError::ZeroBlockSize)
+        ));
+
+        let expected = SparseDescription::from_parts(SegmentType::Data, vec![2, 6, 8]).segments();
+        assert!(test_chunks_match(file, &expected));
+    }
+
+    #[test]
+    fn test_layouts_are_block_aligned() {
+        let file = SparseDescription::from_parts(SegmentType::Data, vec![]).to_file();
+        let block_size = file.as_file().block_size().expect("block size");
+        assert_eq!(BLOCK_SIZE % block_size, 0);
+    }
+
+    #[test]
+    fn set_sparse_allows_holes() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.set_sparse().expect("set sparse");
+        file.set_len(4 * BLOCK_SIZE).expect("set length");
+
+        let expected = SparseDescription::one_segment(SegmentType::Hole, 4 * BLOCK_SIZE);
+        assert!(test_chunks_match(&mut file, &expected.segments()));
+    }
+
+    #[test]
+    fn detects_sparse_files() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let file = desc.to_file();
+        assert!(file.as_file().is_sparse().expect("is sparse"));
+
+        // Only the attribute is checked on windows
+        #[cfg(unix)]
+        {
+            let desc = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE);
+            let file = desc.to_file();
+            file.as_file().sync_all().expect("sync");
+            assert!(!file.as_file().is_sparse().expect("is sparse"));
+        }
+    }
+
+    #[test]
+    fn io_block_size_is_nonzero() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+        assert!(file.io_block_size().expect("io block size") > 0);
+    }
+
+    #[test]
+    fn helpers_read_in_io_blocks() {
+        // Zeros for a whole io block, then for half of one at the end
+        let mut content = vec![1_u8; 8];
+        content.extend([0; 8]);
+        content.extend([1; 4]);
+        content.extend([0; 4]);
+        let mut file = MemoryFile::new(content.clone(), 8);
+
+        let opts = ScanOptions {
+            detect_zeros_in_nonsparse: true,
+        };
+        assert_eq!(
+            file.scan_chunks_opts(opts).expect("scan"),
+            vec![
+                Segment::data(0..8),
+                Segment::hole(8..16),
+                Segment::data(16..24),
+            ]
+        );
+        assert!(file.reads.iter().all(|&len| len <= 8));
+
+        file.reads.clear();
+        let mut copy = std::io::Cursor::new(Vec::new());
+        file.copy_to(&mut copy).expect("copy");
+        assert_eq!(copy.into_inner(), content);
+        assert_eq!(file.reads, vec![8, 8, 8]);
+    }
+
+    #[test]
+    fn absorbs_short_segments() {
+        let segments = vec![
+            Segment::data(0..10000),
+            Segment::hole(10000..10100),
+            Segment::data(10100..20000),
+            Segment::hole(20000..20500),
+            Segment::data(20500..30000),
+            Segment::hole(30000..40000),
+            Segment::data(40000..40050),
+        ];
+
+        assert_eq!(
+            absorb_short_segments(&segments, 1000),
+            vec![Segment::data(0..30000), Segment::hole(30000..40050)]
+        );
+        assert_eq!(absorb_short_segments(&segments, 0), segments);
+        assert_eq!(
+            absorb_short_segments(&segments, u64::MAX),
+            vec![Segment::data(0..40050)]
+        );
+    }
+
+    #[quickcheck]
+    fn scan_and_repunch_matches_scan(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let (layout, drilled) = file
+            .as_file_mut()
+            .scan_and_repunch()
+            .expect("scan and repunch");
+        let hole_len: u64 = layout
+            .iter()
+            .filter(|s| s.is_hole())
+            .map(Segment::len)
+            .sum();
+
+        drilled == hole_len && test_chunks_match(file.as_file_mut(), &layout)
+    }
+
+    #[quickcheck]
+    fn scan_from_matches_tail(desc: SparseDescription, offset: u64) -> bool {
+        let mut file = desc.to_file();
+        let segments = desc.segments();
+        let len = segments.last().map(|s| s.range.end).unwrap_or(0);
+        let offset = offset % (len + BLOCK_SIZE);
+
+        let mut expected: Vec<_> = segments
+            .into_iter()
+            .filter(|s| s.range.end > offset)
+            .collect();
+        if let Some(first) = expected.first_mut() {
+            first.range.start = first.range.start.max(offset);
+        }
+
+        let tail = file
+            .as_file_mut()
+            .scan_chunks_from(offset)
+            .expect("scan from offset");
+        expected == tail
+    }
+
+    #[test]
+    fn stops_at_deadline() {
+        use std::time::Duration;
+
+        let desc = SparseDescription::from_parts(SegmentType::Data, (1..=20).collect());
+        let mut file = desc.to_file();
+        let deadline = Instant::now() + Duration::from_millis(50);
+
+        let mut processed = Vec::new();
+        let result = file
+            .as_file_mut()
+            .for_each_segment_until(deadline, |segment| {
+                // An artificially slow consumer
+                std::thread::sleep(Duration::from_millis(10));
+                processed.push(segment.clone());
+            });
+
+        let offset = match result {
+            Err(ScanError::TimedOut { offset }) => offset,
+            other => panic!("expected a timeout, got {:?}", other),
+        };
+        let processed_end = processed.last().map(|s| s.range.end).unwrap_or(0);
+        assert!(processed.len() < 20);
+        assert_eq!(offset, processed_end);
+
+        // Resuming from the offset picks up the rest of the file
+        let rest = file
+            .as_file_mut()
+            .scan_chunks_from(offset)
+            .expect("resume scan");
+        processed.extend(rest);
+        assert_eq!(processed, desc.segments());
+    }
+
+    #[test]
+    fn stops_slow_scans_at_deadline() {
+        use std::time::Duration;
+
+        let layout =
+            SparseDescription::from_parts(SegmentType::Data, (1..=20).collect()).segments();
+        let mut file = MockSeeks::from_layout(layout.clone(), Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_millis(50);
+
+        // The consumer is fast, it is the seeks that take the time
+        let mut processed = Vec::new();
+        let result =
+            file.for_each_segment_until(deadline, |segment| processed.push(segment.clone()));
+
+        let offset = match result {
+            Err(ScanError::TimedOut { offset }) => offset,
+            other => panic!("expected a timeout, got {:?}", other),
+        };
+        assert!(processed.len() < 10);
+        assert_eq!(offset, processed.last().map(|s| s.range.end).unwrap_or(0));
+
+        let mut file = MockSeeks::from_layout(layout.clone(), Duration::ZERO);
+        processed.extend(file.scan_chunks_from(offset).expect("resume scan"));
+        assert_eq!(processed, layout);
+    }
+
+    #[test]
+    fn scan_iter_handles_stuck_seeks() {
+        // Claims data right where the hole it reported starts
+        let mut file = MockSeeks::new(300, |offset, wanted| match (offset, wanted) {
+            (0, SegmentType::Hole) => Some(100),
+            (100, SegmentType::Data) => Some(100),
+            (100, SegmentType::Hole) => Some(200),
+            (200, SegmentType::Data) => Some(250),
+            (250, SegmentType::Hole) => None,
+            _ => unreachable!(),
+        });
+        let segments: Result<Vec<_>, _> = file.scan_chunks_iter().collect();
+        assert_eq!(
+            segments.expect("scan"),
+            vec![
+                Segment::data(0..200),
+                Segment::hole(200..250),
+                Segment::data(250..300),
+            ]
+        );
+
+        // Seeks that never move, or go backwards, end the scan with an error
+        for seek in [|offset, _| Some(offset), |offset: u64, _| Some(offset / 2)] {
+            let mut file = MockSeeks::new(300, seek);
+            let results: Vec<_> = file.scan_chunks_iter().collect();
+            assert!(matches!(
+                results.last(),
+                Some(Err(ScanError::UnsupportedFileSystem))
+            ));
+        }
+    }
+
+    #[test]
+    fn sparsify_except_keeps_received_ranges() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&vec![0; 8 * BLOCK_SIZE as usize])
+            .expect("write placeholder zeros");
+        let received = [
+            BLOCK_SIZE + 10..2 * BLOCK_SIZE - 10,
+            4 * BLOCK_SIZE..6 * BLOCK_SIZE,
+        ];
+        for range in &received {
+            file.seek(SeekFrom::Start(range.start)).expect("seek");
+            file.write_all(&vec![1; (range.end - range.start) as usize])
+                .expect("write received data");
+        }
+
+        let drilled = file.sparsify_except(&received).expect("sparsify");
+
+        assert_eq!(drilled, 5 * BLOCK_SIZE);
+        let expected =
+            SparseDescription::from_parts(SegmentType::Hole, vec![1, 2, 4, 6, 8]).segments();
+        assert!(test_chunks_match(&mut file, &expected));
+    }
+
+    #[test]
+    fn layout_change_detection() {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE).to_file();
+        let file = file.as_file_mut();
+        let signature = file.layout_signature().expect("signature");
+
+        assert!(!file.layout_changed_since(signature).expect("unchanged"));
+
+        file.drill_hole(BLOCK_SIZE, 2 * BLOCK_SIZE)
+            .expect("drilled hole");
+        assert!(file.layout_changed_since(signature).expect("changed"));
+    }
+
+    #[test]
+    fn cluster_bitmap_round_trip() {
+        let segments = vec![
+            Segment::hole(0..100),
+            Segment::data(100..200),
+            Segment::hole(200..2048),
+            Segment::data(2048..3072),
+            Segment::hole(3072..4500),
+        ];
+
+        let bitmap = to_cluster_bitmap(&segments, 1024, 4500);
+        assert_eq!(bitmap, vec![true, false, true, false, false]);
+
+        let clustered = from_cluster_bitmap(&bitmap, 1024, 4500);
+        assert_eq!(
+            clustered,
+            vec![
+                Segment::data(0..1024),
+                Segment::hole(1024..2048),
+                Segment::data(2048..3072),
+                Segment::hole(3072..4500),
+            ]
+        );
+        assert_eq!(to_cluster_bitmap(&clustered, 1024, 4500), bitmap);
+    }
+
+    #[test]
+    fn observes_block_granularity() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 3, 5]).to_file();
+        assert_eq!(
+            file.as_file_mut()
+                .observed_granularity()
+                .expect("granularity"),
+            BLOCK_SIZE
+        );
+
+        let mut empty = tempfile::tempfile().expect("Unable to create tempfile");
+        assert_eq!(empty.observed_granularity().expect("granularity"), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn opens_files_owned_by_others() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 2]).to_file();
+        // Make sure we don't own the file, if we have the power to do so
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chown(file.path(), Some(65534), Some(65534)).expect("chown");
+        }
+
+        let mut opened = open_for_scan(file.path()).expect("open for scan");
+        let segments = opened.scan_chunks().expect("scan");
+        assert!(test_chunks_match(file.as_file_mut(), &segments));
+
+        // Not owned by us unless we are root
+        open_for_scan("/etc/passwd").expect("open system file");
+    }
+
+    #[test]
+    fn granularities_are_powers_of_two() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 3]).to_file();
+        let (scan, punch) = file.as_file_mut().granularities().expect("granularities");
+
+        assert!(scan.is_power_of_two(), "scan granularity {}", scan);
+        assert!(punch.is_power_of_two(), "punch granularity {}", punch);
+    }
+
+    #[cfg(all(feature = "memmap", target_os = "linux"))]
+    #[test]
+    fn mmap_reads_zeros_after_drilling() {
+        let file = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE).to_file();
+        let mut mmap = unsafe { memmap2::MmapMut::map_mut(file.as_file()) }.expect("map file");
+        let block = BLOCK_SIZE as usize;
+        // Fault the pages in before drilling
+        assert!(mmap.iter().all(|&b| b == 1));
+
+        drill_hole_mmap(&mut mmap, file.as_file(), BLOCK_SIZE, 3 * BLOCK_SIZE).expect("drill hole");
+
+        assert!(mmap[..block].iter().all(|&b| b == 1));
+        assert!(mmap[block..3 * block].iter().all(|&b| b == 0));
+        assert!(mmap[3 * block..].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn reports_current_position_in_hole() {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 2]).to_file();
+        let file = file.as_file_mut();
+
+        file.seek(SeekFrom::Start(10)).expect("seek into data");
+        assert!(!file.current_in_hole().expect("in data"));
+        assert_eq!(file.stream_position().expect("position"), 10);
+
+        file.seek(SeekFrom::Start(BLOCK_SIZE + 10))
+            .expect("seek into hole");
+        assert!(file.current_in_hole().expect("in hole"));
+        assert_eq!(file.stream_position().expect("position"), BLOCK_SIZE + 10);
+
+        file.seek(SeekFrom::Start(2 * BLOCK_SIZE))
+            .expect("seek to end");
+        assert!(!file.current_in_hole().expect("at end"));
+    }
+
+    #[test]
+    fn union_of_complementary_layouts_is_data() {
+        let a = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3, 4]).segments();
+        let b = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2, 3, 4]).segments();
+
+        assert_eq!(
+            union_layout(&[&a, &b]),
+            vec![Segment::data(0..4 * BLOCK_SIZE)]
+        );
+        assert_eq!(union_layout(&[&a, &a]), a);
+        assert_eq!(union_layout(&[]), vec![]);
+    }
+
+    #[test]
+    fn intersection_keeps_common_holes() {
+        let a = SparseDescription::from_parts(SegmentType::Data, vec![1, 4, 6]).segments();
+        let b = SparseDescription::from_parts(SegmentType::Data, vec![2, 5, 6, 8]).segments();
+
+        assert_eq!(
+            intersect_layout(&[&a, &b]),
+            SparseDescription::from_parts(SegmentType::Data, vec![2, 4, 6]).segments()
+        );
+    }
+
+    #[test]
+    fn diffs_segments() {
+        let change = |kind, blocks: Range<u64>| Change {
+            kind,
+            range: blocks.start * BLOCK_SIZE..blocks.end * BLOCK_SIZE,
+        };
+        let old = SparseDescription::from_parts(SegmentType::Data, vec![2, 4, 6]).segments();
+        let new = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3, 4, 8]).segments();
+
+        assert_eq!(
+            diff_segments(&old, &new),
+            vec![
+                change(ChangeKind::Unchanged, 0..1),
+                change(ChangeKind::BecameHole, 1..2),
+                change(ChangeKind::BecameData, 2..3),
+                change(ChangeKind::Unchanged, 3..6),
+                change(ChangeKind::BecameData, 6..8),
+            ]
+        );
+        assert_eq!(
+            diff_segments(&new, &old),
+            vec![
+                change(ChangeKind::Unchanged, 0..1),
+                change(ChangeKind::BecameData, 1..2),
+                change(ChangeKind::BecameHole, 2..3),
+                change(ChangeKind::Unchanged, 3..6),
+                change(ChangeKind::BecameHole, 6..8),
+            ]
+        );
+        assert_eq!(
+            diff_segments(&old, &old),
+            vec![change(ChangeKind::Unchanged, 0..6)]
+        );
+        assert_eq!(diff_segments(&[], &[]), vec![]);
+    }
+
+    #[test]
+    fn fragmented_files_score_higher() {
+        let mut contiguous =
+            SparseDescription::from_parts(SegmentType::Data, vec![8, 16]).to_file();
+        let mut fragmented =
+            SparseDescription::from_parts(SegmentType::Data, (1..=16).collect()).to_file();
+
+        let contiguous = contiguous
+            .as_file_mut()
+            .fragmentation_score()
+            .expect("contiguous score");
+        let fragmented = fragmented
+            .as_file_mut()
+            .fragmentation_score()
+            .expect("fragmented score");
+
+        assert!(contiguous < fragmented, "{} >= {}", contiguous, fragmented);
+    }
+
+    #[test]
+    fn sparsifies_atomically() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let path = dir.path().join("dense");
+        let block = BLOCK_SIZE as usize;
+        let mut content = vec![1; block];
+        content.extend(vec![0; 2 * block]);
+        content.extend(vec![1; block]);
+        let mut file = File::create(&path).expect("create file");
+        file.write_all(&content).expect("write content");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o640))
+                .expect("set permissions");
+        }
+        drop(file);
+
+        let saved = sparsify_in_place_atomic(&path, BLOCK_SIZE).expect("sparsify");
+
+        assert_eq!(saved, 2 * BLOCK_SIZE);
+        assert_eq!(std::fs::read(&path).expect("read back"), content);
+        let mut file = File::open(&path).expect("open sparse file");
+        let expected = SparseDescription::from_parts(SegmentType::Data, vec![1, 3, 4]).segments();
+        assert!(test_chunks_match(&mut file, &expected));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = file.metadata().expect("metadata").permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+        // Only the sparse file is left behind
+        assert_eq!(std::fs::read_dir(dir.path()).expect("list dir").count(), 1);
+    }
+
+    #[test]
+    fn sparsifies_concurrently() {
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let path = dir.path().join("dense");
+        let mut content = vec![1; BLOCK_SIZE as usize];
+        content.extend(vec![0; 2 * BLOCK_SIZE as usize]);
+        std::fs::write(&path, &content).expect("write content");
+
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| sparsify_in_place_atomic(&path, BLOCK_SIZE)))
+                .collect();
+            for thread in threads {
+                thread.join().expect("join").expect("sparsify");
+            }
+        });
+
+        assert_eq!(std::fs::read(&path).expect("read back"), content);
+        assert_eq!(std::fs::read_dir(dir.path()).expect("list dir").count(), 1);
+    }
+
+    #[test]
+    fn only_data_needs_punching() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 3, 4]).to_file();
+        let file = file.as_file_mut();
+
+        assert!(!file
+            .needs_punch(BLOCK_SIZE, 3 * BLOCK_SIZE)
+            .expect("hole range"));
+        assert!(!file
+            .needs_punch(BLOCK_SIZE + 10, 2 * BLOCK_SIZE)
+            .expect("inside hole"));
+        assert!(file.needs_punch(0, BLOCK_SIZE).expect("data range"));
+        assert!(file
+            .needs_punch(2 * BLOCK_SIZE, 3 * BLOCK_SIZE + 1)
+            .expect("overlapping data"));
+        assert!(!file.needs_punch(0, 0).expect("empty range"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sparse_copy_preserves_metadata() {
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let path = dir.path().join("file");
+        let file = File::create(&path).expect("create file");
+        file.set_len(BLOCK_SIZE).expect("set length");
+
+        let name = std::ffi::CString::new("user.drill-press").expect("xattr name");
+        let value = b"sparse";
+        let ret = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            // The filesystem holding the temp dir doesn't do user xattrs
+            return;
+        }
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        file.set_modified(modified).expect("set modified time");
+        drop(file);
+
+        sparsify_in_place_atomic(&path, BLOCK_SIZE).expect("sparsify");
+
+        let file = File::open(&path).expect("open copy");
+        let mut copied = [0_u8; 16];
+        let len = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                copied.as_mut_ptr() as *mut libc::c_void,
+                copied.len(),
+            )
+        };
+        assert!(
+            len >= 0,
+            "xattr missing: {}",
+            std::io::Error::last_os_error()
+        );
+        assert_eq!(&copied[..len as usize], value);
+        assert_eq!(
+            file.metadata()
+                .expect("metadata")
+                .modified()
+                .expect("mtime"),
+            modified
+        );
+    }
+
+    #[quickcheck]
+    fn boundaries_are_segment_starts(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let boundaries = file.as_file_mut().boundaries().expect("boundaries");
+        let starts: Vec<u64> = desc.segments().iter().skip(1).map(Segment::start).collect();
+
+        boundaries == starts
+    }
+
+    #[quickcheck]
+    fn punches_holes_at_own_pace(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        let holes = file.holes_to_punch().expect("scan holes");
+        for hole in holes {
+            file.drill_hole(hole.start, hole.end).expect("drill hole");
+        }
+
+        test_chunks_match(file, &desc.segments())
+    }
+
+    #[test]
+    fn report_is_consistent() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let mut file = desc.to_file();
+        let report = file.as_file_mut().report().expect("report");
+
+        assert_eq!(report.logical_len, 8 * BLOCK_SIZE);
+        assert_eq!(report.data_len, 4 * BLOCK_SIZE);
+        assert_eq!(report.data_len + report.hole_len, report.logical_len);
+        assert_eq!(report.segment_count, 4);
+        assert!(report.allocated_len >= report.data_len);
+        assert!(report.block_size > 0);
+        assert!(report
+            .to_string()
+            .starts_with(&format!("logical_len={} ", 8 * BLOCK_SIZE)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn serde_round_trips(desc: SparseDescription) -> bool {
+        let segments = desc.segments();
+        let json = serde_json::to_string(&segments).expect("serialize");
+        let parsed: Vec<Segment> = serde_json::from_str(&json).expect("deserialize");
+
+        parsed == segments
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_range_as_object() {
+        let segment = Segment::hole(4..8);
+        assert_eq!(
+            serde_json::to_string(&segment).expect("serialize"),
+            r#"{"segment_type":"Hole","range":{"start":4,"end":8}}"#
+        );
+    }
+
+    #[quickcheck]
+    fn scans_in_place(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let position = desc.segments().total_len() / 3;
+        file.seek(SeekFrom::Start(position)).expect("seek");
+
+        let segments = file.scan_chunks_preserving().expect("scan");
+
+        segments == desc.segments() && file.stream_position().expect("position") == position
+    }
+
+    #[quickcheck]
+    fn drills_all_holes_in_place(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let position = desc.segments().total_len() / 2;
+        file.seek(SeekFrom::Start(position)).expect("seek");
+
+        let drilled = file.drill_all_holes().expect("drill all holes");
+
+        drilled == desc.segments().hole_len()
+            && file.stream_position().expect("position") == position
+            && test_chunks_match(file, &desc.segments())
+    }
+
+    #[test]
+    fn punches_zero_blocks() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![]).to_file();
+        let file = file.as_file_mut();
+        let block = vec![1_u8; BLOCK_SIZE as usize];
+        let zeros = vec![0_u8; BLOCK_SIZE as usize];
+        for chunk in [&block, &zeros, &zeros, &block, &zeros] {
+            file.write_all(chunk).expect("write block");
+        }
+        // A partial block at the end, half data and half zeros, which has to
+        // stay data as a whole
+        file.write_all(&block[..100]).expect("write partial data");
+        file.write_all(&zeros[..100]).expect("write partial zeros");
+        file.sync_all().expect("sync");
+
+        let freed = file.punch_zeros(BLOCK_SIZE).expect("punch zeros");
+        assert_eq!(freed, 3 * BLOCK_SIZE);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        file.read_to_end(&mut contents).expect("read back");
+        assert_eq!(contents[..BLOCK_SIZE as usize], block[..]);
+        assert_eq!(
+            contents[3 * BLOCK_SIZE as usize..4 * BLOCK_SIZE as usize],
+            block[..]
+        );
+        assert_eq!(contents[5 * BLOCK_SIZE as usize..][..100], block[..100]);
+        assert!(contents[5 * BLOCK_SIZE as usize + 100..]
+            .iter()
+            .all(|&b| b == 0));
+
+        let segments = file.scan_chunks().expect("scan");
+        assert_eq!(segments.hole_len(), 3 * BLOCK_SIZE);
+        assert_eq!(
+            segments.last(),
+            Some(&Segment::data(5 * BLOCK_SIZE..5 * BLOCK_SIZE + 200))
+        );
+    }
+
+    #[test]
+    fn punches_matching_blocks() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![]).to_file();
+        let file = file.as_file_mut();
+        let block = vec![1_u8; BLOCK_SIZE as usize];
+        let filler = vec![0xff_u8; BLOCK_SIZE as usize];
+        for chunk in [&filler, &block, &filler, &filler] {
+            file.write_all(chunk).expect("write block");
+        }
+        file.sync_all().expect("sync");
+
+        let freed = file
+            .punch_if(BLOCK_SIZE, |b| b.iter().all(|&x| x == 0xff))
+            .expect("punch if");
+        assert_eq!(freed, 3 * BLOCK_SIZE);
+
+        let expected = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2, 4]);
+        assert_eq!(file.scan_chunks().expect("scan"), expected.segments());
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        file.read_to_end(&mut contents).expect("read back");
+        assert_eq!(
+            contents[BLOCK_SIZE as usize..][..BLOCK_SIZE as usize],
+            block[..]
+        );
+        assert_eq!(contents.iter().filter(|&&b| b != 0).count(), block.len());
+    }
+
+    #[test]
+    fn rejects_zero_block_size() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        assert!(matches!(file.scan_both(0), Err(ScanError::ZeroBlockSize)));
+        assert!(matches!(file.punch_zeros(0), Err(ScanError::ZeroBlockSize)));
+        assert!(matches!(
+            file.punch_if(0, |_| true),
+            Err(ScanError::ZeroBlockSize)
+        ));
+        assert_eq!(file.scan_chunks().expect("scan"), desc.segments());
+    }
+
+    #[quickcheck]
+    fn copies_only_data(desc: SparseDescription) -> bool {
+        let mut src = desc.to_file();
+        let mut dst = SparseDescription::from_parts(SegmentType::Data, vec![]).to_file();
+        let copied = src
+            .as_file_mut()
+            .copy_to(dst.as_file_mut())
+            .expect("copy to file");
+
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        src.as_file_mut()
+            .copy_to(&mut cursor)
+            .expect("copy to cursor");
+        let mut contents = Vec::new();
+        src.as_file_mut().seek(SeekFrom::Start(0)).expect("seek");
+        src.as_file_mut()
+            .read_to_end(&mut contents)
+            .expect("read back");
+
+        copied == desc.segments().data_len()
+            && cursor.into_inner() == contents
+            && test_chunks_match(dst.as_file_mut(), &desc.segments())
+    }
+
+    #[quickcheck]
+    fn copies_ranges(desc: SparseDescription, start: u16, len: u16) -> bool {
+        let mut src = desc.to_file();
+        let mut contents = Vec::new();
+        src.as_file_mut().seek(SeekFrom::Start(0)).expect("seek");
+        src.as_file_mut()
+            .read_to_end(&mut contents)
+            .expect("read back");
+
+        let total = contents.len() as u64;
+        let start = (start as u64 * 64).min(total);
+        let end = (start + len as u64 * 64).min(total);
+        let mut cursor = std::io::Cursor::new(vec![0xff; total as usize]);
+        let copied = src
+            .as_file_mut()
+            .copy_range_to(start..end, &mut cursor)
+            .expect("copy range");
+
+        // Data in the range is copied, everything else is left as it was
+        let mut expected = vec![0xff; total as usize];
+        let mut expected_copied = 0;
+        for data in desc.segments().data() {
+            let data = data.start.max(start) as usize..data.end.min(end) as usize;
+            if !data.is_empty() {
+                expected[data.clone()].copy_from_slice(&contents[data.clone()]);
+                expected_copied += data.len() as u64;
+            }
+        }
+
+        copied == expected_copied && cursor.into_inner() == expected
+    }
+
+    #[quickcheck]
+    fn scan_range_matches_window(desc: SparseDescription, start: u64, len: u64) -> bool {
+        let mut file = desc.to_file();
+        let segments = desc.segments();
+        let file_len = segments.total_len();
+        let start = start % (file_len + BLOCK_SIZE);
+        let end = start + len % (file_len + BLOCK_SIZE);
+
+        let expected: Vec<Segment> = segments
+            .into_iter()
+            .map(|s| Segment {
+                segment_type: s.segment_type,
+                range: s.range.start.max(start)..s.range.end.min(end),
+            })
+            .filter(|s| s.range.start < s.range.end)
+            .collect();
+
+        file.as_file_mut()
+            .scan_chunks_range(start..end)
+            .expect("scan range")
+            == expected
+    }
+
+    #[quickcheck]
+    fn canonical_layouts_are_canonical(layout: arbitrary::CanonicalLayout) -> bool {
+        let segments = layout.0;
+        segments.first().is_none_or(|s| s.range.start == 0)
+            && segments.iter().all(|s| !s.is_empty())
+            && coalesced(segments.iter().cloned()) == segments
+            && check_contiguous(&segments, segments.total_len()).is_ok()
+    }
+
+    #[quickcheck]
+    fn coalesce_is_idempotent(layout: ArbitraryLayout) -> bool {
+        let mut once = layout.segments();
+        coalesce(&mut once);
+        let mut twice = once.clone();
+        coalesce(&mut twice);
+        once == twice
+    }
+
+    #[quickcheck]
+    fn coalesce_merges_all_neighbours(layout: ArbitraryLayout) -> bool {
+        let segments = layout.segments();
+        let mut merged = segments.clone();
+        coalesce(&mut merged);
+
+        merged
+            .windows(2)
+            .all(|w| w[0].segment_type != w[1].segment_type && w[0].range.end == w[1].range.start)
+            && merged.first().map(Segment::start) == segments.first().map(Segment::start)
+            && merged.total_len() == segments.total_len()
+            && merged.data_len() == segments.data_len()
+    }
+
+    #[quickcheck]
+    fn diff_with_self_is_unchanged(layout: ArbitraryLayout) -> bool {
+        let segments = layout.segments();
+        let mut merged = segments.clone();
+        coalesce(&mut merged);
+
+        [
+            diff_segments(&segments, &segments),
+            diff_segments(&segments, &merged),
+        ]
+        .iter()
+        .all(|changes| {
+            changes.iter().all(|c| c.kind == ChangeKind::Unchanged)
+                && changes.len() <= 1
+                && changes
+                    .iter()
+                    .map(|c| c.range.end - c.range.start)
+                    .sum::<u64>()
+                    == segments.total_len()
+        })
+    }
+
+    #[test]
+    fn applies_holes() {
+        let mut segments = vec![
+            Segment::data(0..10),
+            Segment::hole(10..20),
+            Segment::data(20..30),
+            Segment::hole(30..40),
+        ];
+        apply_hole(&mut segments, 5, 25);
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0..5),
+                Segment::hole(5..25),
+                Segment::data(25..30),
+                Segment::hole(30..40),
+            ]
+        );
+
+        // Clamped to the end, and merged with the hole already there
+        apply_hole(&mut segments, 28, 100);
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0..5),
+                Segment::hole(5..25),
+                Segment::data(25..28),
+                Segment::hole(28..40),
+            ]
+        );
+
+        // Empty and backwards ranges change nothing
+        let before = segments.clone();
+        apply_hole(&mut segments, 2, 2);
+        apply_hole(&mut segments, 3, 1);
+        assert_eq!(segments, before);
+    }
+
+    #[quickcheck]
+    fn applied_holes_match_drilled_files(desc: SparseDescription, start: u8, len: u8) -> bool {
+        let mut file = desc.to_drilled_file();
+        let start = start as u64 * BLOCK_SIZE / 4;
+        let end = start + len as u64 * BLOCK_SIZE / 4;
+        // Only whole blocks are freed
+        let (start, end) = (
+            start.div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+            end / BLOCK_SIZE * BLOCK_SIZE,
+        );
+        if start < end {
+            file.as_file().drill_hole(start, end).expect("drill hole");
+        }
+
+        let mut segments = desc.segments();
+        apply_hole(&mut segments, start, end);
+        test_chunks_match(file.as_file_mut(), &segments)
+    }
+
+    #[test]
+    fn iterates_typed_segments() {
+        let segments = vec![Segment::hole(0..10), Segment::data(10..30)];
+        assert_eq!(
+            segments.iter_typed().collect::<Vec<_>>(),
+            vec![(SegmentType::Hole, 0..10), (SegmentType::Data, 10..30)]
+        );
+    }
+
+    #[test]
+    fn coalesces_neighbours() {
+        let mut segments = vec![
+            Segment::data(0..10),
+            Segment::data(10..20),
+            Segment::hole(20..30),
+            Segment::hole(30..40),
+            Segment::hole(40..50),
+            Segment::data(50..60),
+        ];
+        coalesce(&mut segments);
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0..20),
+                Segment::hole(20..50),
+                Segment::data(50..60),
+            ]
+        );
+    }
+
+    #[test]
+    fn displays_segments() {
+        use crate::segment::HumanBytes;
+
+        assert_eq!(
+            Segment::data(0..4096).to_string(),
+            "data   0..4096 (4.0 KiB)"
+        );
+        assert_eq!(
+            Segment::hole(4096..4608).to_string(),
+            "hole   4096..4608 (512 B)"
+        );
+        assert_eq!(
+            Segment::data(0..3 << 29).to_string(),
+            "data   0..1610612736 (1.5 GiB)"
+        );
+        assert_eq!(HumanBytes(u64::MAX).to_string(), "16.0 EiB");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_scan_matches_sync() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let temp = desc.to_file();
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp.path())
+            .await
+            .expect("open");
+
+        AsyncSparseFile::drill_hole(&file, 0, 2 * BLOCK_SIZE)
+            .await
+            .expect("drill hole");
+        let segments = AsyncSparseFile::scan_chunks(&mut file).await.expect("scan");
+
+        let expected = SparseDescription::from_parts(SegmentType::Hole, vec![3, 5, 8]);
+        assert_eq!(segments, expected.segments());
+        assert_eq!(
+            segments,
+            temp.reopen().expect("reopen").scan_chunks().expect("scan")
+        );
+    }
+
+    #[test]
+    fn splits_segments() {
+        let segment = Segment::hole(10..20);
+        let (head, tail) = segment.split_at(15).expect("split");
+        assert_eq!(head.range, 10..15);
+        assert_eq!(tail.range, 15..20);
+        assert!(head.is_hole() && tail.is_hole());
+
+        assert_eq!(
+            segment.split_at(11).map(|(_, tail)| tail.range),
+            Some(11..20)
+        );
+        for offset in [0, 10, 20, 25] {
+            assert_eq!(segment.split_at(offset), None);
+        }
+    }
+
+    #[test]
+    fn intersects_segments() {
+        let data = Segment::data(10..20);
+
+        assert_eq!(data.intersect(&Segment::hole(15..30)), Some(15..20));
+        assert_eq!(data.intersect(&Segment::data(0..30)), Some(10..20));
+        assert_eq!(data.intersect(&data), Some(10..20));
+        assert!(data.overlaps(&Segment::hole(19..20)));
+
+        // Touching segments share no bytes
+        assert_eq!(data.intersect(&Segment::hole(20..30)), None);
+        assert!(!data.overlaps(&Segment::hole(0..10)));
+        assert!(!data.overlaps(&Segment::hole(15..15)));
+    }
+
+    #[quickcheck]
+    fn finds_segment_at_offset(desc: SparseDescription, offset: u64) -> bool {
+        let segments = desc.segments();
+        let offset = offset % (segments.total_len() + BLOCK_SIZE);
+
+        segments.find_segment_at(offset) == segments.iter().find(|s| s.contains(&offset))
+    }
+
+    #[test]
+    fn converts_segment_types_to_bools() {
+        for segment_type in [SegmentType::Hole, SegmentType::Data] {
+            assert_eq!(SegmentType::from_data(segment_type.is_data()), segment_type);
+            assert_ne!(segment_type.is_data(), segment_type.is_hole());
+        }
+        assert!(SegmentType::from_data(true).is_data());
+        assert!(SegmentType::from_data(false).is_hole());
+    }
+
+    #[test]
+    fn verifies_drilled_holes() {
+        let desc = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        let drilled = file
+            .drill_hole_verified(BLOCK_SIZE, 3 * BLOCK_SIZE)
+            .expect("drill aligned");
+        assert_eq!(drilled, 2 * BLOCK_SIZE);
+
+        // Too small to free a block, so only zeroed
+        let drilled = file
+            .drill_hole_verified(3 * BLOCK_SIZE + 100, 3 * BLOCK_SIZE + 200)
+            .expect("drill unaligned");
+        assert_eq!(drilled, 0);
+        let mut zeroed = [1_u8; 100];
+        file.seek(SeekFrom::Start(3 * BLOCK_SIZE + 100))
+            .expect("seek");
+        file.read_exact(&mut zeroed).expect("read back");
+        assert_eq!(zeroed, [0; 100]);
+    }
+
+    #[test]
+    fn drills_several_holes() {
+        let desc = SparseDescription::one_segment(SegmentType::Data, 8 * BLOCK_SIZE);
+        let mut file = desc.to_file();
+        file.as_file()
+            .drill_holes(&[
+                BLOCK_SIZE..2 * BLOCK_SIZE,
+                3 * BLOCK_SIZE..3 * BLOCK_SIZE,
+                4 * BLOCK_SIZE..6 * BLOCK_SIZE,
+            ])
+            .expect("drill holes");
+
+        let expected = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 4, 6, 8]);
+        assert_eq!(
+            file.as_file_mut().scan_chunks().expect("scan"),
+            expected.segments()
+        );
+
+        let read_only = File::open(file.path()).expect("reopen read only");
+        match read_only.drill_holes(&[0..0, 0..BLOCK_SIZE]) {
+            Err(ScanError::DrillFailed { index, range, .. }) => {
+                assert_eq!((index, range), (1, 0..BLOCK_SIZE))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn exposes_io_error_kind() {
+        let missing = open_for_scan("/this/path/does/not/exist").expect_err("open missing file");
+        assert_eq!(missing.io_kind(), Some(std::io::ErrorKind::NotFound));
+        assert_eq!(ScanError::UnsupportedPlatform.io_kind(), None);
+    }
+
+    #[test]
+    fn splits_segments_on_block_boundaries() {
+        let segments = vec![
+            Segment::data(0..4),
+            Segment::hole(4..10),
+            Segment::data(10..11),
+        ];
+        assert_eq!(
+            segments.block_aligned(4).collect::<Vec<_>>(),
+            vec![
+                Segment::data(0..4),
+                Segment::hole(4..8),
+                Segment::hole(8..10),
+                Segment::data(10..11),
+            ]
+        );
+        assert_eq!(segments.block_aligned(16).collect::<Vec<_>>(), segments);
+        assert_eq!(
+            vec![Segment::hole(u64::MAX - 3..u64::MAX)]
+                .block_aligned(1 << 63)
+                .collect::<Vec<_>>(),
+            vec![Segment::hole(u64::MAX - 3..u64::MAX)]
+        );
+    }
+
+    #[quickcheck]
+    fn block_aligned_segments_cover_the_same_bytes(
+        desc: SparseDescription,
+        block_size: u16,
+    ) -> bool {
+        let block_size = block_size as u64 + 1;
+        let segments = desc.segments();
+        let pieces: Vec<_> = segments.block_aligned(block_size).collect();
+
+        coalesced(pieces.iter().cloned()) == coalesced(segments.iter().cloned())
+            && pieces
+                .iter()
+                .all(|p| p.range.start / block_size == (p.range.end - 1) / block_size)
+    }
+
+    #[test]
+    fn totals_segment_lengths() {
+        let segments =
+            SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]).segments();
+        assert_eq!(segments.data_len(), 4 * BLOCK_SIZE);
+        assert_eq!(segments.hole_len(), 4 * BLOCK_SIZE);
+        assert_eq!(segments.total_len(), 8 * BLOCK_SIZE);
+
+        let tail = segments[1..].to_vec();
+        assert_eq!(tail.total_len(), 6 * BLOCK_SIZE);
+
+        let empty: Vec<Segment> = Vec::new();
+        assert_eq!(
+            (empty.data_len(), empty.hole_len(), empty.total_len()),
+            (0, 0, 0)
+        );
+    }
+
+    #[quickcheck]
+    fn sparse_reader_reads_logical_content(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let mut expected = Vec::new();
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        file.as_file_mut()
+            .read_to_end(&mut expected)
+            .expect("read file");
+
+        let mut reader = SparseReader::scan(file.reopen().expect("reopen")).expect("scan");
+        let len = reader.len();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).expect("read sparse");
+
+        len == desc.segments().total_len() && contents == expected
+    }
+
+    #[quickcheck]
+    fn truncates_trailing_hole(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        let mut expected = desc.segments();
+        if expected.last().is_some_and(Segment::is_hole) {
+            expected.pop();
+        }
+
+        let len = file.truncate_trailing_hole().expect("truncate");
+        len == expected.total_len()
+            && file.metadata().expect("metadata").len() == len
+            && test_chunks_match(file, &expected)
+    }
+
+    #[quickcheck]
+    fn scan_iter_matches_scan(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let segments: Result<Vec<Segment>, ScanError> =
+            file.as_file_mut().scan_chunks_iter().collect();
+
+        segments.expect("scan") == desc.segments()
+    }
+
+    #[quickcheck]
+    fn fills_holes(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let mut before = Vec::new();
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        file.read_to_end(&mut before).expect("read before");
+        file.seek(SeekFrom::Start(1)).expect("seek");
+
+        let filled = file.fill_holes().expect("fill holes");
+        let position = file.stream_position().expect("position");
+        let segments = file.scan_chunks().expect("scan");
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        let mut after = Vec::new();
+        file.read_to_end(&mut after).expect("read after");
+
+        filled == desc.segments().hole_len()
+            && position == 1
+            && segments.holes().count() == 0
+            && segments.total_len() == desc.segments().total_len()
+            && before == after
+    }
+
+    #[quickcheck]
+    fn scans_paths(desc: SparseDescription) -> bool {
+        let file = desc.to_file();
+        scan_path(file.path()).expect("scan path") == desc.segments()
+    }
+
+    #[test]
+    fn scans_into_reused_buffer() {
+        let descs = [
+            SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]),
+            SparseDescription::from_parts(SegmentType::Hole, vec![1]),
+            SparseDescription::from_parts(SegmentType::Data, vec![]),
+        ];
+        let mut buf = Vec::new();
+        for desc in descs {
+            desc.to_file()
+                .as_file_mut()
+                .scan_chunks_into(&mut buf)
+                .expect("scan");
+            assert_eq!(buf, desc.segments());
+        }
+    }
+
+    #[test]
+    fn sorts_segments() {
+        let mut segments = vec![
+            Segment::data(10..20),
+            Segment::hole(0..10),
+            Segment::data(0..5),
+            Segment::data(20..30),
+            Segment::hole(20..30),
+        ];
+        segments.sort();
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0..5),
+                Segment::hole(0..10),
+                Segment::data(10..20),
+                Segment::hole(20..30),
+                Segment::data(20..30),
+            ]
+        );
+    }
+
+    #[quickcheck]
+    fn scans_are_sorted(desc: SparseDescription) -> bool {
+        let segments = desc.to_file().as_file_mut().scan_chunks().expect("scan");
+        segments.windows(2).all(|w| w[0] < w[1])
+    }
+
+    #[test]
+    fn detects_zeros_in_dense_files() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        let block = file.io_block_size().expect("io block size") as usize;
+        let mut content = vec![0_u8; block * 4];
+        content[..block].fill(1);
+        content[block * 3..].fill(2);
+        file.write_all(&content).expect("write");
+        assert!(!file.is_sparse().expect("is_sparse"));
+
+        let plain = file.scan_chunks_opts(ScanOptions::default()).expect("scan");
+        assert_eq!(plain, file.scan_chunks().expect("scan"));
+
+        let opts = ScanOptions {
+            detect_zeros_in_nonsparse: true,
+        };
+        let block = block as u64;
+        assert_eq!(
+            file.scan_chunks_opts(opts).expect("scan"),
+            vec![
+                Segment::data(0..block),
+                Segment::hole(block..block * 3),
+                Segment::data(block * 3..block * 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn extends_files_with_holes() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.set_sparse().expect("set sparse");
+        file.write_all(&vec![1; BLOCK_SIZE as usize])
+            .expect("write data");
+        file.extend_with_hole(3 * BLOCK_SIZE).expect("extend");
+
+        assert_eq!(
+            file.scan_chunks().expect("scan"),
+            vec![
+                Segment::data(0..BLOCK_SIZE),
+                Segment::hole(BLOCK_SIZE..4 * BLOCK_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn hashes_content_not_layout() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::Hasher;
+
+        fn hash(file: &mut File) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            file.hash_data(&mut hasher).expect("hash");
+            hasher.finish()
+        }
+
+        let sparse = SparseFileBuilder::new()
+            .data(BLOCK_SIZE)
+            .hole(3 * BLOCK_SIZE)
+            .data(2 * BLOCK_SIZE);
+        let mut sparse_file = tempfile::tempfile().expect("Unable to create tempfile");
+        sparse
+            .build_drilled(&mut sparse_file)
+            .expect("write sparse");
+
+        let mut dense_file = tempfile::tempfile().expect("Unable to create tempfile");
+        let mut content = vec![1_u8; 6 * BLOCK_SIZE as usize];
+        content[BLOCK_SIZE as usize..4 * BLOCK_SIZE as usize].fill(0);
+        dense_file.write_all(&content).expect("write dense");
+        assert_eq!(hash(&mut sparse_file), hash(&mut dense_file));
+
+        // The zeros are still counted
+        let mut shifted_file = tempfile::tempfile().expect("Unable to create tempfile");
+        SparseFileBuilder::new()
+            .data(BLOCK_SIZE)
+            .hole(2 * BLOCK_SIZE)
+            .data(3 * BLOCK_SIZE)
+            .write_to(&mut shifted_file)
+            .expect("write shifted");
+        assert_ne!(hash(&mut sparse_file), hash(&mut shifted_file));
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn rejects_backwards_drills() {
+        let desc = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE);
+        let mut file = desc.to_file();
+
+        assert!(matches!(
+            file.as_file().drill_hole(3 * BLOCK_SIZE, BLOCK_SIZE),
+            Err(ScanError::InvalidRange { start, end }) if start == 3 * BLOCK_SIZE && end == BLOCK_SIZE
+        ));
+        assert!(matches!(
+            file.as_file()
+                .drill_holes(&[0..0, 3 * BLOCK_SIZE..BLOCK_SIZE]),
+            Err(ScanError::DrillFailed { index: 1, .. })
+        ));
+        assert_eq!(
+            file.as_file_mut().scan_chunks().expect("scan"),
+            desc.segments()
+        );
+    }
+
+    #[quickcheck]
+    fn scan_progress_reports_each_segment(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let mut reported = Vec::new();
+        let segments = file
+            .as_file_mut()
+            .scan_chunks_with_progress(|s| reported.push(s.clone()))
+            .expect("scan");
+
+        segments == desc.segments() && reported == segments
+    }
+
+    #[test]
+    fn scan_iter_stops_early() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![2, 3, 5, 8]);
+        let mut file = desc.to_file();
+        let mut iter = file.as_file_mut().scan_chunks_iter();
+
+        let first = iter.next().expect("a segment").expect("scan");
+        assert_eq!(first, desc.segments()[0]);
+    }
+
+    #[quickcheck]
+    fn scan_checked_accepts_scan(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        file.scan_chunks_checked().expect("checked scan") == file.scan_chunks().expect("scan")
+    }
+
+    #[test]
+    fn finds_non_contiguous_segments() {
+        let gap = |segments: &[Segment], len| match check_contiguous(segments, len) {
+            Err(ScanError::NonContiguous { gap }) => Some(gap),
+            Ok(()) => None,
+            Err(e) => panic!("unexpected error {:?}", e),
+        };
+
+        let contiguous = [Segment::data(0..10), Segment::hole(10..20)];
+        assert_eq!(gap(&contiguous, 20), None);
+        assert_eq!(gap(&[], 0), None);
+        assert_eq!(gap(&[], 5), Some(0..5));
+        assert_eq!(gap(&contiguous, 30), Some(20..30));
+        assert_eq!(gap(&contiguous, 15), Some(15..20));
+        assert_eq!(gap(&contiguous[1..], 20), Some(0..10));
+        let gappy = [Segment::data(0..10), Segment::hole(12..20)];
+        assert_eq!(gap(&gappy, 20), Some(10..12));
+        let overlapping = [Segment::data(0..10), Segment::hole(8..20)];
+        assert_eq!(gap(&overlapping, 20), Some(8..10));
+    }
+
+    #[test]
+    fn measures_sparse_ratio() {
+        let ratio = |desc: SparseDescription| {
+            desc.to_file()
+                .as_file_mut()
+                .sparse_ratio()
+                .expect("sparse ratio")
+        };
+
+        assert_eq!(
+            ratio(SparseDescription::from_parts(SegmentType::Data, vec![])),
+            0.0
+        );
+        assert_eq!(
+            ratio(SparseDescription::one_segment(
+                SegmentType::Data,
+                4 * BLOCK_SIZE
+            )),
+            0.0
+        );
+        assert_eq!(
+            ratio(SparseDescription::one_segment(
+                SegmentType::Hole,
+                4 * BLOCK_SIZE
+            )),
+            1.0
+        );
+        assert_eq!(
+            ratio(SparseDescription::from_parts(
+                SegmentType::Data,
+                vec![2, 3, 5, 8]
+            )),
+            0.5
+        );
+    }
+
+    #[test]
+    fn measures_lengths() {
+        let mut empty = tempfile::tempfile().expect("Unable to create tempfile");
+        assert_eq!(empty.logical_len().expect("logical len"), 0);
+        assert_eq!(empty.allocated_len().expect("allocated len"), 0);
+
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![2, 3, 5, 8]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        assert_eq!(file.logical_len().expect("logical len"), 8 * BLOCK_SIZE);
+        assert_eq!(file.allocated_len().expect("allocated len"), 4 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn reports_reclaimed_space() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![4]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        file.sync_all().expect("sync");
+
+        let baseline = file.sparse_len().expect("sparse len");
+        file.drill_hole(BLOCK_SIZE, 3 * BLOCK_SIZE)
+            .expect("drill hole");
+        let reclaimed = file.reclaimed_since(baseline).expect("reclaimed");
+
+        assert!(reclaimed > 0);
+        assert!(reclaimed.unsigned_abs().abs_diff(2 * BLOCK_SIZE) <= BLOCK_SIZE);
+    }
+
+    #[test]
+    fn verifies_layout() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        assert!(file.matches_layout(&desc.segments()).expect("match"));
+        assert!(file
+            .verify_layout(&desc.segments())
+            .expect("verify")
+            .is_empty());
+
+        let mut expected = desc.segments();
+        expected[1].segment_type = SegmentType::Data;
+        assert!(!file.matches_layout(&expected).expect("match"));
+        assert_eq!(
+            file.verify_layout(&expected).expect("verify"),
+            vec![Segment::hole(2 * BLOCK_SIZE..3 * BLOCK_SIZE)]
+        );
+    }
+
+    #[quickcheck]
+    fn one_big_segment(segment_type: SegmentType) -> bool {
+        let desc = SparseDescription::one_segment(segment_type, 3545868);
+
+        test_round_trips(desc)
     }
 }
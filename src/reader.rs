@@ -0,0 +1,93 @@
+//! Reading the logical content of a sparse file without reading its holes
+use super::*;
+
+/// A reader over the full logical content of a file, producing zeros for
+/// the holes without reading them from the underlying reader
+///
+/// The layout is given up front, usually from
+/// [`scan_chunks`](SparseFile::scan_chunks), and the underlying reader is
+/// only read (and seeked) within its `Data` segments. Exactly as many bytes
+/// as the layout covers are produced, starting from the start of the first
+/// segment.
+#[derive(Debug)]
+pub struct SparseReader<R> {
+    inner: R,
+    segments: Vec<Segment>,
+    /// The index of the segment `position` is in
+    index: usize,
+    position: u64,
+    /// Whether `inner` is known to be at `position`
+    in_sync: bool,
+}
+
+impl<R: Read + Seek> SparseReader<R> {
+    /// Reads `inner` according to `segments`, which must be in order and
+    /// without gaps between them
+    pub fn new(inner: R, segments: Vec<Segment>) -> Self {
+        let position = segments.first().map(Segment::start).unwrap_or(0);
+        SparseReader {
+            inner,
+            segments,
+            index: 0,
+            position,
+            in_sync: false,
+        }
+    }
+
+    /// The total number of bytes this reader produces
+    pub fn len(&self) -> u64 {
+        self.segments.total_len()
+    }
+
+    /// Returns true if this reader produces no bytes at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Unwraps this reader, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: SparseFile> SparseReader<R> {
+    /// Scans `file` and reads its whole logical content
+    pub fn scan(mut file: R) -> Result<Self, ScanError> {
+        let segments = file.scan_chunks()?;
+        Ok(SparseReader::new(file, segments))
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self
+            .segments
+            .get(self.index)
+            .is_some_and(|s| s.range.end <= self.position)
+        {
+            self.index += 1;
+        }
+        let segment = match self.segments.get(self.index) {
+            Some(segment) => segment,
+            None => return Ok(0),
+        };
+
+        let want = buf.len().min((segment.range.end - self.position) as usize);
+        let read = if segment.is_hole() {
+            buf[..want].fill(0);
+            self.in_sync = false;
+            want
+        } else {
+            if !self.in_sync {
+                self.inner.seek(SeekFrom::Start(self.position))?;
+                self.in_sync = true;
+            }
+            match self.inner.read(&mut buf[..want])? {
+                0 if want > 0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                read => read,
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,556 @@
+//! The segments making up the layout of a sparse file, and the range math on
+//! them
+//!
+//! Nothing here touches the filesystem, so it is all available without the
+//! `std` feature, needing only `alloc`.
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+use core::ops::Range;
+use core::slice::Iter;
+
+/// Flag for determining if a segment is a hole, or if it contains data
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SegmentType {
+    Hole,
+    Data,
+}
+
+impl SegmentType {
+    pub fn opposite(&self) -> Self {
+        match self {
+            SegmentType::Hole => SegmentType::Data,
+            SegmentType::Data => SegmentType::Hole,
+        }
+    }
+
+    /// `Data` if `is_data` is true, otherwise `Hole`, the inverse of
+    /// [`is_data`](SegmentType::is_data)
+    pub fn from_data(is_data: bool) -> Self {
+        if is_data {
+            SegmentType::Data
+        } else {
+            SegmentType::Hole
+        }
+    }
+
+    /// Returns true if this is a Hole
+    pub fn is_hole(&self) -> bool {
+        *self == SegmentType::Hole
+    }
+
+    /// Returns true if this is Data
+    pub fn is_data(&self) -> bool {
+        *self == SegmentType::Data
+    }
+}
+
+impl fmt::Display for SegmentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            SegmentType::Hole => "hole",
+            SegmentType::Data => "data",
+        })
+    }
+}
+
+/// Describes the location of a chunk in the file, as well as indicating if it
+/// contains data or is a hole
+///
+/// With the `serde` feature the range is serialized as a
+/// `{ "start": .., "end": .. }` object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Segment {
+    /// Marks this segment as either containing a hole, or containing data
+    pub segment_type: SegmentType,
+    /// the (half-open) range of bytes in the file covered by this segment
+    pub range: Range<u64>,
+}
+
+/// Displays as e.g. `data   0..4096 (4.0 KiB)`
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<6} {}..{} ({})",
+            self.segment_type,
+            self.range.start,
+            self.range.end,
+            HumanBytes(self.len())
+        )
+    }
+}
+
+/// Orders segments by where they start, then by where they end, the order
+/// [`scan_chunks`](crate::SparseFile::scan_chunks) returns them in
+///
+/// Segments covering the same range put the hole first, only so the order
+/// agrees with `Eq`.
+impl Ord for Segment {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.range
+            .start
+            .cmp(&other.range.start)
+            .then(self.range.end.cmp(&other.range.end))
+            .then(self.is_data().cmp(&other.is_data()))
+    }
+}
+
+impl PartialOrd for Segment {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Renders a number of bytes in binary units, e.g. `512 B` or `4.0 KiB`
+pub(crate) struct HumanBytes(pub(crate) u64);
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// An iterator over the ranges of a file of a specific [`SegmentType`]
+#[derive(Debug, Clone)]
+pub struct SegmentIter<'a> {
+    segment_type: SegmentType,
+    iter: Iter<'a, Segment>,
+}
+
+impl<'a> Iterator for SegmentIter<'a> {
+    type Item = &'a Range<u64>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        for segment in self.iter.by_ref() {
+            if segment.segment_type == self.segment_type {
+                return Some(&segment.range);
+            }
+        }
+        None
+    }
+}
+
+/// An extention trait to filter segments by Hole or Data segments
+pub trait Segments {
+    fn data(&self) -> SegmentIter<'_>;
+    fn holes(&self) -> SegmentIter<'_>;
+    /// The total length of the `Data` segments
+    fn data_len(&self) -> u64;
+    /// The total length of the `Hole` segments
+    fn hole_len(&self) -> u64;
+    /// The span from the start of the first segment to the end of the last
+    fn total_len(&self) -> u64;
+    /// Finds the segment covering `offset` with a binary search, relying on
+    /// the segments being in order, or `None` if no segment covers it
+    fn find_segment_at(&self, offset: u64) -> Option<&Segment>;
+    /// The segments split at every multiple of `block_size`, so that no
+    /// piece crosses from one block into the next
+    ///
+    /// Each piece has the type of the segment it came from, and segments that
+    /// already fit within a block are passed through unsplit. Neighbouring
+    /// segments are never merged. `block_size` must not be 0.
+    fn block_aligned(&self, block_size: u64) -> impl Iterator<Item = Segment> + '_;
+    /// Every segment as a `(type, range)` pair, for matching on the type
+    fn iter_typed(&self) -> impl Iterator<Item = (SegmentType, Range<u64>)> + '_;
+}
+
+impl Segments for Vec<Segment> {
+    fn data(&self) -> SegmentIter<'_> {
+        SegmentIter {
+            segment_type: SegmentType::Data,
+            iter: self.iter(),
+        }
+    }
+    fn holes(&self) -> SegmentIter<'_> {
+        SegmentIter {
+            segment_type: SegmentType::Hole,
+            iter: self.iter(),
+        }
+    }
+    fn data_len(&self) -> u64 {
+        self.data().map(|r| r.end - r.start).sum()
+    }
+    fn hole_len(&self) -> u64 {
+        self.holes().map(|r| r.end - r.start).sum()
+    }
+    fn total_len(&self) -> u64 {
+        match (self.first(), self.last()) {
+            (Some(first), Some(last)) => last.range.end - first.range.start,
+            _ => 0,
+        }
+    }
+    fn find_segment_at(&self, offset: u64) -> Option<&Segment> {
+        let index = self.partition_point(|s| s.range.end <= offset);
+        self.get(index).filter(|s| s.contains(&offset))
+    }
+    fn block_aligned(&self, block_size: u64) -> impl Iterator<Item = Segment> + '_ {
+        assert!(block_size > 0, "block size must not be 0");
+        self.iter().flat_map(move |segment| {
+            let segment_type = segment.segment_type;
+            let (mut start, end) = (segment.range.start, segment.range.end);
+            core::iter::from_fn(move || {
+                if start >= end {
+                    return None;
+                }
+                let next_block = (start / block_size + 1).checked_mul(block_size);
+                let piece_end = next_block.map_or(end, |b| b.min(end));
+                let piece = Segment {
+                    segment_type,
+                    range: start..piece_end,
+                };
+                start = piece_end;
+                Some(piece)
+            })
+        })
+    }
+    fn iter_typed(&self) -> impl Iterator<Item = (SegmentType, Range<u64>)> + '_ {
+        self.iter().map(|s| (s.segment_type, s.range.clone()))
+    }
+}
+
+/// Pairs each segment with the type of the segment before it, or `None` for
+/// the first segment, making it easy to react to transitions between holes
+/// and data
+pub fn with_prev(segments: &[Segment]) -> impl Iterator<Item = (Option<SegmentType>, &Segment)> {
+    core::iter::once(None)
+        .chain(segments.iter().map(|s| Some(s.segment_type)))
+        .zip(segments)
+}
+
+/// Combines the layouts of several files into one, where a range is `Data` if
+/// it is data in any of the inputs and a `Hole` only where every input has a
+/// hole
+///
+/// This is intended for layouts of files of the same length. Should they
+/// differ the output covers the longest, with the missing tails of the
+/// shorter layouts treated as holes.
+pub fn union_layout(layouts: &[&[Segment]]) -> Vec<Segment> {
+    let end = layouts
+        .iter()
+        .filter_map(|l| l.last())
+        .map(|s| s.range.end)
+        .max()
+        .unwrap_or(0);
+    overlay_data(layouts, end)
+}
+
+/// Finds the holes common to several layouts, returning a layout where a
+/// range is a `Hole` only if every input agrees it is a hole, and `Data`
+/// otherwise
+///
+/// This finds the regions that are free in every replica of a file. Within
+/// the inputs it is the same as [`union_layout`], but if the layouts differ in
+/// length the output is clamped to the shortest, as only that part is covered
+/// by every input.
+pub fn intersect_layout(layouts: &[&[Segment]]) -> Vec<Segment> {
+    let end = layouts
+        .iter()
+        .map(|l| l.last().map(|s| s.range.end).unwrap_or(0))
+        .min()
+        .unwrap_or(0);
+    overlay_data(layouts, end)
+}
+
+/// Overlays layouts up to `end`, marking a range as `Data` if any of them has
+/// data there
+fn overlay_data(layouts: &[&[Segment]], end: u64) -> Vec<Segment> {
+    let mut boundaries: Vec<u64> = layouts
+        .iter()
+        .flat_map(|l| l.iter().map(|s| s.range.start))
+        .filter(|&b| b < end)
+        .chain([0, end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut cursors = vec![0; layouts.len()];
+    let mut segments = Vec::new();
+    for range in boundaries.windows(2) {
+        let is_data = layouts.iter().zip(&mut cursors).any(|(layout, cursor)| {
+            while layout.get(*cursor).is_some_and(|s| s.range.end <= range[0]) {
+                *cursor += 1;
+            }
+            layout.get(*cursor).is_some_and(|s| s.is_data())
+        });
+        let segment_type = SegmentType::from_data(is_data);
+        push_segment(&mut segments, segment_type, range[0]..range[1]);
+    }
+    segments
+}
+
+/// How a range of a file changed between two scans, see [`diff_segments`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChangeKind {
+    /// The range was a hole and is now data
+    BecameData,
+    /// The range was data and is now a hole
+    BecameHole,
+    /// The range is the same type in both scans
+    Unchanged,
+}
+
+/// A range of a file and how it changed between two scans
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// the (half-open) range of bytes in the file that changed this way
+    pub range: Range<u64>,
+}
+
+/// Compares the layouts of a file from before and after some change, finding
+/// the ranges that became data, became holes, or stayed the same
+///
+/// Both layouts must be sorted and contiguous, as returned by
+/// [`scan_chunks`](crate::SparseFile::scan_chunks). The output covers the longer of
+/// the two, with the missing tail of the shorter one treated as a hole, and
+/// neighbouring ranges that changed the same way are merged.
+pub fn diff_segments(old: &[Segment], new: &[Segment]) -> Vec<Change> {
+    let mut changes: Vec<Change> = Vec::new();
+    let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+    let mut offset = 0;
+    loop {
+        while old.next_if(|s| s.range.end <= offset).is_some() {}
+        while new.next_if(|s| s.range.end <= offset).is_some() {}
+        let end = match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (Some(a), Some(b)) => a.range.end.min(b.range.end),
+            (Some(s), None) | (None, Some(s)) => s.range.end,
+        };
+
+        let type_of = |s: Option<&&Segment>| s.map_or(SegmentType::Hole, |s| s.segment_type);
+        let kind = match (type_of(old.peek()), type_of(new.peek())) {
+            (SegmentType::Hole, SegmentType::Data) => ChangeKind::BecameData,
+            (SegmentType::Data, SegmentType::Hole) => ChangeKind::BecameHole,
+            _ => ChangeKind::Unchanged,
+        };
+        match changes.last_mut() {
+            Some(last) if last.kind == kind => last.range.end = end,
+            _ => changes.push(Change {
+                kind,
+                range: offset..end,
+            }),
+        }
+        offset = end;
+    }
+    changes
+}
+
+/// Merges neighbouring segments of the same type in place, e.g. to keep an
+/// in-memory layout canonical after changing the type of some segments
+///
+/// The order of the segments and the bytes they cover are unchanged.
+pub fn coalesce(segments: &mut Vec<Segment>) {
+    *segments = coalesced(segments.drain(..));
+}
+
+/// Updates a layout to match the file after
+/// [`drill_hole`](crate::SparseFile::drill_hole)`(start, end)`, without
+/// scanning it again
+///
+/// The parts of the segments between `start` and `end` become a hole, and
+/// neighbouring segments of the same type are merged. As drilling never
+/// extends a file, the hole is clamped to the end of the layout. The layout
+/// is a model of the file only, a filesystem may free less than the whole
+/// range, see [`drill_hole_verified`](crate::SparseFile::drill_hole_verified).
+pub fn apply_hole(segments: &mut Vec<Segment>, start: u64, end: u64) {
+    let end = end.min(segments.last().map_or(0, |s| s.range.end));
+    if start >= end {
+        return;
+    }
+
+    let hole = Segment::hole(start..end);
+    let pieces = core::mem::take(segments).into_iter().flat_map(|segment| {
+        let drilled = match segment.intersect(&hole) {
+            Some(drilled) => drilled,
+            None => return [Some(segment), None, None],
+        };
+        let before = segment.range.start..drilled.start;
+        let after = drilled.end..segment.range.end;
+        [
+            (!before.is_empty()).then_some(Segment {
+                segment_type: segment.segment_type,
+                range: before,
+            }),
+            Some(Segment::hole(drilled)),
+            (!after.is_empty()).then_some(Segment {
+                segment_type: segment.segment_type,
+                range: after,
+            }),
+        ]
+    });
+    *segments = coalesced(pieces.flatten());
+}
+
+/// Converts a layout into a cluster allocation bitmap, as used by cluster
+/// based image formats such as qcow2
+///
+/// Entry `i` of the bitmap covers the bytes from `i * cluster_size` to
+/// `(i + 1) * cluster_size`, and is true (allocated) if any `Data` segment
+/// overlaps it. The bitmap has just enough entries to cover `total_len` bytes.
+///
+/// # Panics
+///
+/// Panics if `cluster_size` is zero
+pub fn to_cluster_bitmap(segments: &[Segment], cluster_size: u64, total_len: u64) -> Vec<bool> {
+    let clusters = total_len.div_ceil(cluster_size);
+    let mut bitmap = vec![false; clusters as usize];
+
+    for data in segments.iter().filter(|s| s.is_data()) {
+        let end = data.range.end.min(total_len);
+        if data.range.start >= end {
+            continue;
+        }
+        let first = data.range.start / cluster_size;
+        let last = (end - 1) / cluster_size;
+        bitmap[first as usize..=last as usize].fill(true);
+    }
+
+    bitmap
+}
+
+/// Converts a cluster allocation bitmap back into a layout, the inverse of
+/// [`to_cluster_bitmap`]
+///
+/// Allocated clusters become `Data` and the rest `Hole`, with neighbouring
+/// clusters of the same type merged into one segment. The last segment is
+/// clamped to `total_len`, and any clusters beyond it are ignored.
+pub fn from_cluster_bitmap(bitmap: &[bool], cluster_size: u64, total_len: u64) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for (i, &allocated) in bitmap.iter().enumerate() {
+        let start = i as u64 * cluster_size;
+        if start >= total_len {
+            break;
+        }
+        let segment_type = SegmentType::from_data(allocated);
+        push_segment(
+            &mut segments,
+            segment_type,
+            start..(start + cluster_size).min(total_len),
+        );
+    }
+    segments
+}
+
+impl Segment {
+    /// A `Data` segment covering `range`
+    pub fn data(range: Range<u64>) -> Self {
+        Segment {
+            segment_type: SegmentType::Data,
+            range,
+        }
+    }
+
+    /// A `Hole` segment covering `range`
+    pub fn hole(range: Range<u64>) -> Self {
+        Segment {
+            segment_type: SegmentType::Hole,
+            range,
+        }
+    }
+
+    /// Returns true if the provided offset is within the range of bytes this
+    /// segment specifies
+    pub fn contains(&self, offset: &u64) -> bool {
+        self.range.contains(offset)
+    }
+
+    /// Returns true if this segment is a Hole
+    pub fn is_hole(&self) -> bool {
+        self.segment_type.is_hole()
+    }
+
+    /// Returns true if this segment contains data
+    pub fn is_data(&self) -> bool {
+        self.segment_type.is_data()
+    }
+
+    /// The starting position of this segment
+    pub fn start(&self) -> u64 {
+        self.range.start
+    }
+
+    /// The number of bytes in this segment
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    /// Returns true if this segment covers no bytes
+    ///
+    /// Segments returned by a scan are never empty, but ones built by hand
+    /// can be.
+    pub fn is_empty(&self) -> bool {
+        self.range.start == self.range.end
+    }
+
+    /// Splits this segment in two at `offset`, both halves keeping its type
+    ///
+    /// Returns `None` unless `offset` is strictly inside the segment, so that
+    /// neither half would be empty.
+    pub fn split_at(&self, offset: u64) -> Option<(Segment, Segment)> {
+        if offset <= self.range.start || offset >= self.range.end {
+            return None;
+        }
+        let half = |range| Segment {
+            segment_type: self.segment_type,
+            range,
+        };
+        Some((half(self.range.start..offset), half(offset..self.range.end)))
+    }
+
+    /// Returns true if this segment and `other` share any bytes, whatever
+    /// their types
+    pub fn overlaps(&self, other: &Segment) -> bool {
+        self.intersect(other).is_some()
+    }
+
+    /// The bytes covered by both this segment and `other`, whatever their
+    /// types, or `None` if there are none
+    pub fn intersect(&self, other: &Segment) -> Option<Range<u64>> {
+        let start = self.range.start.max(other.range.start);
+        let end = self.range.end.min(other.range.end);
+        (start < end).then_some(start..end)
+    }
+}
+
+/// Merges neighbouring segments of the same type
+pub(crate) fn coalesced(segments: impl IntoIterator<Item = Segment>) -> Vec<Segment> {
+    let mut output = Vec::new();
+    for segment in segments {
+        push_segment(&mut output, segment.segment_type, segment.range);
+    }
+    output
+}
+
+/// Appends a segment to the list, extending the last segment instead if it
+/// has the same type
+pub(crate) fn push_segment(
+    segments: &mut Vec<Segment>,
+    segment_type: SegmentType,
+    range: Range<u64>,
+) {
+    match segments.last_mut() {
+        Some(last) if last.segment_type == segment_type && last.range.end == range.start => {
+            last.range.end = range.end;
+        }
+        _ => segments.push(Segment {
+            segment_type,
+            range,
+        }),
+    }
+}
@@ -0,0 +1,133 @@
+//! Counting of the work done by scans, for profiling
+use std::cell::Cell;
+use std::ops::AddAssign;
+use std::time::Duration;
+
+/// Totals of the work done by one or more scans, filled in by
+/// [`scan_chunks_with_stats`](crate::SparseFile::scan_chunks_with_stats)
+///
+/// Only the syscalls a platform actually makes are counted, so e.g.
+/// `device_io_control_calls` is always 0 on unix.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    /// The number of scans
+    pub scans: u64,
+    /// Calls to `lseek`, including `SEEK_DATA`/`SEEK_HOLE` probes
+    pub lseek_calls: u64,
+    /// Calls to `fstat`
+    pub fstat_calls: u64,
+    /// Calls to `ftruncate`
+    pub ftruncate_calls: u64,
+    /// Calls to `fstatvfs`
+    pub fstatvfs_calls: u64,
+    /// Calls to `fallocate`
+    pub fallocate_calls: u64,
+    /// Calls to `ioctl`
+    pub ioctl_calls: u64,
+    /// Calls to `fcntl`
+    pub fcntl_calls: u64,
+    /// Calls to `madvise`
+    pub madvise_calls: u64,
+    /// Calls to `flistxattr`, `fgetxattr` and `fsetxattr`
+    pub xattr_calls: u64,
+    /// Calls to `DeviceIoControl`
+    pub device_io_control_calls: u64,
+    /// Calls to `SetFileInformationByHandle`
+    pub set_file_information_calls: u64,
+    /// The total length of the files scanned
+    pub bytes_scanned: u64,
+    /// Wall time spent scanning
+    pub elapsed: Duration,
+}
+
+impl AddAssign for ScanStats {
+    fn add_assign(&mut self, other: Self) {
+        self.scans += other.scans;
+        self.lseek_calls += other.lseek_calls;
+        self.fstat_calls += other.fstat_calls;
+        self.ftruncate_calls += other.ftruncate_calls;
+        self.fstatvfs_calls += other.fstatvfs_calls;
+        self.fallocate_calls += other.fallocate_calls;
+        self.ioctl_calls += other.ioctl_calls;
+        self.fcntl_calls += other.fcntl_calls;
+        self.madvise_calls += other.madvise_calls;
+        self.xattr_calls += other.xattr_calls;
+        self.device_io_control_calls += other.device_io_control_calls;
+        self.set_file_information_calls += other.set_file_information_calls;
+        self.bytes_scanned += other.bytes_scanned;
+        self.elapsed += other.elapsed;
+    }
+}
+
+impl ScanStats {
+    /// The syscall counts that have built up since `earlier`
+    fn since(&self, earlier: &Self) -> Self {
+        ScanStats {
+            lseek_calls: self.lseek_calls - earlier.lseek_calls,
+            fstat_calls: self.fstat_calls - earlier.fstat_calls,
+            ftruncate_calls: self.ftruncate_calls - earlier.ftruncate_calls,
+            fstatvfs_calls: self.fstatvfs_calls - earlier.fstatvfs_calls,
+            fallocate_calls: self.fallocate_calls - earlier.fallocate_calls,
+            ioctl_calls: self.ioctl_calls - earlier.ioctl_calls,
+            fcntl_calls: self.fcntl_calls - earlier.fcntl_calls,
+            madvise_calls: self.madvise_calls - earlier.madvise_calls,
+            xattr_calls: self.xattr_calls - earlier.xattr_calls,
+            device_io_control_calls: self.device_io_control_calls - earlier.device_io_control_calls,
+            set_file_information_calls: self.set_file_information_calls
+                - earlier.set_file_information_calls,
+            ..Default::default()
+        }
+    }
+}
+
+/// A syscall made by a platform module
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Syscall {
+    Lseek,
+    Fstat,
+    Ftruncate,
+    Fstatvfs,
+    Fallocate,
+    Ioctl,
+    Fcntl,
+    Madvise,
+    Xattr,
+    DeviceIoControl,
+    SetFileInformation,
+}
+
+thread_local! {
+    /// Every syscall made on this thread, scans only look at the difference
+    static SYSCALLS: Cell<ScanStats> = Cell::new(ScanStats::default());
+}
+
+/// Counts a syscall made on this thread
+pub(crate) fn record(syscall: Syscall) {
+    SYSCALLS.with(|counts| {
+        let mut stats = counts.get();
+        match syscall {
+            Syscall::Lseek => stats.lseek_calls += 1,
+            Syscall::Fstat => stats.fstat_calls += 1,
+            Syscall::Ftruncate => stats.ftruncate_calls += 1,
+            Syscall::Fstatvfs => stats.fstatvfs_calls += 1,
+            Syscall::Fallocate => stats.fallocate_calls += 1,
+            Syscall::Ioctl => stats.ioctl_calls += 1,
+            Syscall::Fcntl => stats.fcntl_calls += 1,
+            Syscall::Madvise => stats.madvise_calls += 1,
+            Syscall::Xattr => stats.xattr_calls += 1,
+            Syscall::DeviceIoControl => stats.device_io_control_calls += 1,
+            Syscall::SetFileInformation => stats.set_file_information_calls += 1,
+        }
+        counts.set(stats);
+    })
+}
+
+/// Runs `f`, returning the syscalls it made on this thread along with its
+/// result
+pub(crate) fn counting<R>(f: impl FnOnce() -> R) -> (ScanStats, R) {
+    let before = SYSCALLS.with(Cell::get);
+    let result = f();
+    let after = SYSCALLS.with(Cell::get);
+    (after.since(&before), result)
+}
@@ -1,6 +1,5 @@
 use super::*;
 
-use std::io::{Seek, SeekFrom, Write};
 use tempfile::NamedTempFile;
 
 use quickcheck::{Arbitrary, Gen};
@@ -9,9 +8,9 @@ const MAX_SPLITS: usize = 50;
 
 // minum hole size varies by file system
 #[cfg(unix)]
-const BLOCK_SIZE: u64 = 4 * 1024;
+pub const BLOCK_SIZE: u64 = 4 * 1024;
 #[cfg(windows)]
-const BLOCK_SIZE: u64 = 64 * 1024;
+pub const BLOCK_SIZE: u64 = 64 * 1024;
 
 #[derive(Clone, Debug)]
 pub struct SparseDescription {
@@ -59,35 +58,27 @@ impl SparseDescription {
 
     pub fn to_file(&self) -> NamedTempFile {
         let mut temp = NamedTempFile::new().expect("Unable to create tempfile");
+        self.builder()
+            .write_to(temp.as_file_mut())
+            .expect("Unable to write sparse file");
+        temp
+    }
 
-        // Special handling to enable sparsity on windows
-        #[cfg(windows)]
-        {
-            use std::process::Command;
-            Command::new("fsutil")
-                .arg("sparse")
-                .arg("setflag")
-                .arg(temp.path())
-                .output()
-                .expect("Unable to set the sparse flag on the tempfile");
-        }
+    /// Like `to_file`, but with every hole explicitly drilled
+    pub fn to_drilled_file(&self) -> NamedTempFile {
+        let mut temp = NamedTempFile::new().expect("Unable to create tempfile");
+        self.builder()
+            .build_drilled(temp.as_file_mut())
+            .expect("Unable to write sparse file");
+        temp
+    }
 
-        let file = temp.as_file_mut();
-        // Iterate through the SparseDescription
-        for segment in self.segments().data() {
-            file.seek(SeekFrom::Start(segment.start))
-                .expect("Unable to seek in file");
-            let length = segment.end - segment.start;
-            let buffer = vec![1_u8; length as usize];
-            file.write_all(&buffer[..])
-                .expect("Unable to write bytes to file");
+    fn builder(&self) -> SparseFileBuilder {
+        let mut builder = SparseFileBuilder::new();
+        for segment in self.segments() {
+            builder = builder.segment(segment.segment_type, segment.len());
         }
-
-        let last = self.split_points.last().copied().unwrap_or_default();
-        temp.as_file_mut()
-            .set_len(last as u64 * BLOCK_SIZE)
-            .expect("Unable to set length of file");
-        temp
+        builder
     }
 }
 
@@ -107,12 +98,171 @@ impl Arbitrary for SparseDescription {
     }
 }
 
-impl Arbitrary for SegmentType {
+/// A stand-in file of length `len` whose `seek_data` and `seek_hole` are
+/// answered by `seek`, for driving the generic scans without a filesystem
+pub struct MockSeeks {
+    pub len: u64,
+    /// Finds the next segment of the given type at or after an offset
+    pub seek: Box<dyn FnMut(u64, SegmentType) -> Option<u64>>,
+    position: u64,
+}
+
+impl MockSeeks {
+    pub fn new(len: u64, seek: impl FnMut(u64, SegmentType) -> Option<u64> + 'static) -> Self {
+        MockSeeks {
+            len,
+            seek: Box::new(seek),
+            position: 0,
+        }
+    }
+
+    /// Answers the seeks from `layout`, sleeping for `delay` before each
+    pub fn from_layout(layout: Vec<Segment>, delay: std::time::Duration) -> Self {
+        MockSeeks::new(layout.total_len(), move |offset, wanted| {
+            std::thread::sleep(delay);
+            layout
+                .iter()
+                .find(|s| s.segment_type == wanted && s.range.end > offset)
+                .map(|s| s.range.start.max(offset))
+        })
+    }
+}
+
+impl Read for MockSeeks {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Seek for MockSeeks {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.len.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+        };
+        Ok(self.position)
+    }
+}
+
+impl SparseFile for MockSeeks {
+    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        self.scan_chunks_iter().collect()
+    }
+
+    fn seek_data(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        Ok((self.seek)(offset, SegmentType::Data))
+    }
+
+    fn seek_hole(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        Ok((self.seek)(offset, SegmentType::Hole))
+    }
+
+    fn drill_hole(&self, _start: u64, _end: u64) -> Result<(), ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    fn io_block_size(&self) -> Result<u64, ScanError> {
+        Ok(BLOCK_SIZE)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        Ok(BLOCK_SIZE)
+    }
+}
+
+/// A dense in-memory file with a chosen `io_block_size`, recording the size
+/// of every read, for checking the helpers that read content use it
+pub struct MemoryFile {
+    content: std::io::Cursor<Vec<u8>>,
+    io_block_size: u64,
+    pub reads: Vec<usize>,
+}
+
+impl MemoryFile {
+    pub fn new(content: Vec<u8>, io_block_size: u64) -> Self {
+        MemoryFile {
+            content: std::io::Cursor::new(content),
+            io_block_size,
+            reads: Vec::new(),
+        }
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads.push(buf.len());
+        self.content.read(buf)
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.content.seek(pos)
+    }
+}
+
+impl SparseFile for MemoryFile {
+    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        let len = self.content.get_ref().len() as u64;
+        Ok([Segment::data(0..len)]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect())
+    }
+
+    fn drill_hole(&self, _start: u64, _end: u64) -> Result<(), ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    fn is_sparse(&self) -> Result<bool, ScanError> {
+        Ok(false)
+    }
+
+    fn io_block_size(&self) -> Result<u64, ScanError> {
+        Ok(self.io_block_size)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        Ok(self.io_block_size)
+    }
+}
+
+/// A layout of non-empty segments covering `0..n` with no gaps, which unlike
+/// a `SparseDescription` can have neighbouring segments of the same type
+///
+/// Only for testing the pure segment functions, it can't be written to a file.
+#[derive(Clone, Debug)]
+pub struct ArbitraryLayout {
+    parts: Vec<(SegmentType, u8)>,
+}
+
+impl ArbitraryLayout {
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut start = 0;
+        self.parts
+            .iter()
+            .map(|(segment_type, len)| {
+                let end = start + *len as u64 + 1;
+                let segment = Segment {
+                    segment_type: *segment_type,
+                    range: start..end,
+                };
+                start = end;
+                segment
+            })
+            .collect()
+    }
+}
+
+impl Arbitrary for ArbitraryLayout {
     fn arbitrary(g: &mut Gen) -> Self {
-        if bool::arbitrary(g) {
-            SegmentType::Hole
-        } else {
-            SegmentType::Data
+        ArbitraryLayout {
+            parts: Vec::arbitrary(g),
         }
     }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.parts.shrink().map(|parts| ArbitraryLayout { parts }))
+    }
 }
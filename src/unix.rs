@@ -4,88 +4,653 @@ use super::*;
 
 use std::fs::File;
 use std::io::Error;
-use std::os::unix::io::AsRawFd;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
 
 use errno::errno;
-use libc::{c_int, lseek, off_t, EINVAL, ENXIO, SEEK_END};
+use libc::{c_int, off_t, EINTR, EINVAL, ENXIO, SEEK_END};
+#[cfg(target_os = "linux")]
+use {libc::ENOTSUP, std::ffi::CStr};
+
+#[cfg(target_os = "linux")]
+mod fiemap;
 
 cfg_if::cfg_if! {
     // libc module for macos is missing these, values stolen from _seek_set.h
     if #[cfg(target_os = "macos")]{
         const SEEK_HOLE: c_int  = 3;
         const SEEK_DATA: c_int  = 4;
+    } else if #[cfg(any(target_os = "netbsd", target_os = "openbsd"))] {
+        // nor are they in the libc module for the other BSDs, values from
+        // NetBSD's unistd.h. OpenBSD has neither, so they fail with EINVAL
+        // and the filesystem is reported as unsupported
+        const SEEK_DATA: c_int = 3;
+        const SEEK_HOLE: c_int = 4;
     } else {
         use libc::{SEEK_DATA, SEEK_HOLE};
     }
 }
 
-impl SparseFile for File {
+impl<T: Read + Seek + AsRawFd> SparseFile for T {
     fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
-        // Create our output vec
-        let mut tags: Vec<Segment> = Vec::new();
+        let mut segments = Vec::new();
+        self.scan_chunks_into(&mut segments)?;
+        Ok(segments)
+    }
+
+    fn scan_chunks_into(&mut self, buf: &mut Vec<Segment>) -> Result<(), ScanError> {
+        buf.clear();
+        let fd = self.as_raw_fd();
+        let end = file_len(fd)?;
+
+        scan_with(
+            0,
+            end,
+            |offset, seek_type| safe_lseek(fd, offset, seek_type),
+            |segment| buf.push(segment),
+        )
+    }
+
+    fn scan_chunks_range(&mut self, range: Range<u64>) -> Result<Vec<Segment>, ScanError> {
         // Extract the raw fd from the file
         let fd = self.as_raw_fd();
         // Find the end
-        let end = safe_lseek(fd, 0, SEEK_END)?.unwrap_or(0);
+        let end = file_len(fd)?.min(range.end);
+
+        let mut segments = Vec::new();
+        scan_with(
+            range.start,
+            end,
+            |offset, seek_type| safe_lseek(fd, offset, seek_type),
+            |segment| segments.push(segment),
+        )?;
+        Ok(segments)
+    }
+
+    fn scan_chunks_with_progress(
+        &mut self,
+        mut on_segment: impl FnMut(&Segment),
+    ) -> Result<Vec<Segment>, ScanError> {
+        let fd = self.as_raw_fd();
+        let end = file_len(fd)?;
+
+        let mut segments = Vec::new();
+        scan_with(
+            0,
+            end,
+            |offset, seek_type| safe_lseek(fd, offset, seek_type),
+            |segment| {
+                on_segment(&segment);
+                segments.push(segment);
+            },
+        )?;
+        Ok(segments)
+    }
+
+    fn sparse_len(&mut self) -> Result<u64, ScanError> {
+        Ok(fstat(self.as_raw_fd())?.st_blocks as u64 * 512)
+    }
+
+    fn scan_end(&mut self) -> Result<u64, ScanError> {
+        file_len(self.as_raw_fd())
+    }
+
+    fn is_sparse(&self) -> Result<bool, ScanError> {
+        let stat = fstat(self.as_raw_fd())?;
+        Ok((stat.st_blocks as u64 * 512) < stat.st_size as u64)
+    }
+
+    fn seek_data(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        safe_lseek(self.as_raw_fd(), offset, SEEK_DATA)
+    }
+
+    fn seek_hole(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        safe_lseek(self.as_raw_fd(), offset, SEEK_HOLE)
+    }
+
+    fn scan_chunks_robust(&mut self, content_fallback: bool) -> Result<Vec<Segment>, ScanError> {
+        let segments = self.scan_chunks()?;
+        let allocated = self.sparse_len()?;
+
+        if is_plausible(&segments, allocated) {
+            return Ok(segments);
+        }
+
+        #[cfg(feature = "log")]
+        log::warn!(
+            "filesystem reports more data than the {} bytes allocated on disk, \
+             SEEK_HOLE/SEEK_DATA results are likely wrong",
+            allocated
+        );
 
-        if end == 0 {
-            return Ok(vec![]);
+        if !content_fallback {
+            return Ok(segments);
         }
 
-        // Our seeking loop assumes that we know what type the previous segment
-        // is, so grab the first hole and if it does not exist or is not at the
-        // start add then the file starts with a data block.
-        let mut last_seek = safe_lseek(fd, 0, SEEK_HOLE)?.unwrap_or(end);
-        let mut last_type = SegmentType::Hole;
-        if last_seek > 0 {
-            tags.push(Segment {
-                segment_type: SegmentType::Data,
-                range: 0..last_seek,
+        let end = segments.last().map(|s| s.range.end).unwrap_or(0);
+        let everything = [Segment::data(0..end)];
+        let block_size = self.io_block_size()?;
+        find_zero_blocks(self, &everything, block_size)
+    }
+
+    fn io_block_size(&self) -> Result<u64, ScanError> {
+        Ok(fstat(self.as_raw_fd())?.st_blksize as u64)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        block_size(self.as_raw_fd())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn scan_chunks_detailed(&mut self) -> Result<Vec<DetailedSegment>, ScanError> {
+        let fd = self.as_raw_fd();
+        let len = fstat(fd)?.st_size as u64;
+        let extents = fiemap::extents(fd)?;
+        Ok(fiemap::detailed_segments(&extents, len))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn physical_extents(&mut self) -> Result<Vec<PhysicalExtent>, ScanError> {
+        let extents = fiemap::extents(self.as_raw_fd())?;
+        Ok(extents.iter().map(PhysicalExtent::from).collect())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reflink_to(&self, dst: &File) -> Result<(), ScanError> {
+        use libc::{EOPNOTSUPP, EXDEV};
+
+        // _IOW(0x94, 9, int)
+        const FICLONE: libc::c_ulong = 0x4004_9409;
+
+        // The argument is the source descriptor itself, not a pointer to it
+        let src = self.as_raw_fd() as usize as *mut libc::c_void;
+        match unsafe { with_syscalls(|s| s.ioctl(dst.as_raw_fd(), FICLONE, src)) } {
+            Ok(_) => Ok(()),
+            Err(EOPNOTSUPP | EXDEV) => Err(ScanError::UnsupportedFileSystem),
+            Err(errno) => Err(Error::from_raw_os_error(errno).into()),
+        }
+    }
+
+    fn extend_with_hole(&self, additional: u64) -> Result<(), ScanError> {
+        let fd = self.as_raw_fd();
+        let len = (fstat(fd)?.st_size as u64).saturating_add(additional);
+        let len = off_t::try_from(len).map_err(|_| Error::from_raw_os_error(libc::EFBIG))?;
+        retry_interrupted(|| with_syscalls(|s| s.ftruncate(fd, len)))
+            .map_err(|errno| allocation_error(Error::from_raw_os_error(errno)))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        use libc::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+
+        check_range(start, end)?;
+        retry_interrupted(|| {
+            with_syscalls(|s| {
+                s.fallocate(
+                    self.as_raw_fd(),
+                    FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                    start as off_t,
+                    (end - start) as off_t,
+                )
             })
+        })
+        .map_err(|errno| allocation_error(Error::from_raw_os_error(errno)))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    fn reserve(&self, start: u64, len: u64) -> Result<(), ScanError> {
+        use libc::EOPNOTSUPP;
+
+        // fallocate rejects an empty region with EINVAL
+        if len == 0 {
+            return Ok(());
         }
+        let fd = self.as_raw_fd();
+        match retry_interrupted(|| {
+            with_syscalls(|s| s.fallocate(fd, 0, start as off_t, len as off_t))
+        }) {
+            Ok(()) => Ok(()),
+            Err(EOPNOTSUPP) => Err(ScanError::UnsupportedFileSystem),
+            Err(errno) => Err(allocation_error(Error::from_raw_os_error(errno))),
+        }
+    }
 
-        while last_seek < end {
-            let seek_type = match last_type {
-                SegmentType::Hole => SEEK_DATA,
-                SegmentType::Data => SEEK_HOLE,
+    #[cfg(target_os = "macos")]
+    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        use std::os::unix::fs::FileExt;
+
+        check_range(start, end)?;
+        if start == end {
+            return Ok(());
+        }
+
+        // F_PUNCHHOLE fails with EINVAL unless the region is block aligned,
+        // so only the aligned interior is punched and any data in the
+        // unaligned edges (within the file) is overwritten with zeros instead.
+        // Edges that are already holes are skipped, so they aren't allocated
+        let fd = self.as_raw_fd();
+        // The descriptor stays open for as long as `self` is borrowed
+        let file = BorrowedFile::new(unsafe { BorrowedFd::borrow_raw(fd) });
+        let aligned = align_inward(start..end, block_size(fd)?);
+        let len = fstat(fd)?.st_size as u64;
+        let (head, tail) = if aligned.is_empty() {
+            (start..end, end..end)
+        } else {
+            (start..aligned.start, aligned.end..end)
+        };
+        for edge in [head, tail] {
+            let edge = edge.start.min(len)..edge.end.min(len);
+            if edge.is_empty() {
+                continue;
+            }
+            let data = match safe_lseek(fd, edge.start, SEEK_DATA)? {
+                Some(data) if data < edge.end => data,
+                _ => continue,
             };
+            file.file
+                .write_all_at(&vec![0; (edge.end - data) as usize], data)
+                .map_err(allocation_error)?;
+        }
+
+        if aligned.is_empty() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "log")]
+        log::debug!(
+            "punching hole from {} to {} ({} bytes)",
+            aligned.start,
+            aligned.end,
+            aligned.end - aligned.start
+        );
 
-            let next_seek = safe_lseek(fd, last_seek, seek_type)?.unwrap_or(end);
-            tags.push(Segment {
-                segment_type: last_type,
-                range: last_seek..next_seek,
-            });
-            last_seek = next_seek;
-            last_type = last_type.opposite();
+        // The region is aligned, so EINVAL means the filesystem can't punch
+        // holes at all, as with lseek
+        let len = aligned.end - aligned.start;
+        match retry_interrupted(|| with_syscalls(|s| s.punch_hole(fd, aligned.start, len))) {
+            Ok(()) => Ok(()),
+            Err(EINVAL) => Err(ScanError::UnsupportedFileSystem),
+            Err(errno) => Err(allocation_error(Error::from_raw_os_error(errno))),
         }
-        Ok(tags)
     }
 
-    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    #[cfg(any(target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
     fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        check_range(start, end)?;
+        // There is no way to punch holes in a file here, NetBSD's fdiscard
+        // is only a hint that filesystems are free to ignore
+        Err(ScanError::UnsupportedPlatform)
+    }
+}
+
+/// A file descriptor borrowed for scanning and drilling, without taking
+/// ownership of it like wrapping it in a [`File`] would
+///
+/// Reads and seeks go to the underlying file description, so move the seek
+/// position seen by every other user of the descriptor.
+#[derive(Debug)]
+pub struct BorrowedFile<'fd> {
+    // Never dropped, as the descriptor is only borrowed
+    file: ManuallyDrop<File>,
+    _fd: PhantomData<BorrowedFd<'fd>>,
+}
+
+impl<'fd> BorrowedFile<'fd> {
+    pub fn new(fd: BorrowedFd<'fd>) -> Self {
+        BorrowedFile {
+            file: ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) }),
+            _fd: PhantomData,
+        }
+    }
+}
+
+impl<'fd> From<BorrowedFd<'fd>> for BorrowedFile<'fd> {
+    fn from(fd: BorrowedFd<'fd>) -> Self {
+        BorrowedFile::new(fd)
+    }
+}
+
+impl AsFd for BorrowedFile<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl Read for BorrowedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for BorrowedFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AsRawFd for BorrowedFile<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// The seeking loop behind `scan_chunks`, covering `start..end` with a
+/// [`SegmentStepper`], with `lseek` abstracted out as `seek` so that it can
+/// be driven by something other than a real file
+///
+/// Each segment is passed to `on_segment` once the next one is found.
+fn scan_with<S, P>(start: u64, end: u64, mut seek: S, mut on_segment: P) -> Result<(), ScanError>
+where
+    S: FnMut(u64, c_int) -> Result<Option<u64>, ScanError>,
+    P: FnMut(Segment),
+{
+    let mut stepper = SegmentStepper::new(start..end, None);
+    while let Some(segment) = stepper.next(|offset, wanted| match wanted {
+        SegmentType::Data => seek(offset, SEEK_DATA),
+        SegmentType::Hole => seek(offset, SEEK_HOLE),
+    })? {
+        on_segment(segment);
+    }
+    Ok(())
+}
+
+/// Checks that the data reported by a scan could actually fit in the space
+/// the file occupies on disk
+///
+/// Allows some slack, as small files may be stored inline in metadata and
+/// the allocation of freshly written data can lag behind.
+fn is_plausible(segments: &[Segment], allocated: u64) -> bool {
+    const SLACK: u64 = 64 * 1024;
+    let data: u64 = segments
+        .iter()
+        .filter(|s| s.is_data())
+        .map(Segment::len)
+        .sum();
+    data <= allocated.saturating_mul(2).saturating_add(SLACK)
+}
+
+fn fstat(fd: c_int) -> Result<libc::stat, ScanError> {
+    retry_interrupted(|| with_syscalls(|s| s.fstat(fd)))
+        .map_err(|errno| Error::from_raw_os_error(errno).into())
+}
+
+/// The allocation unit of the filesystem holding the file
+///
+/// This is the fragment size from `fstatvfs`, which is what space is
+/// allocated (and freed) in, unlike `st_blksize` which is only a hint for
+/// efficient I/O. Some filesystems leave it as 0, so the block size is used
+/// then.
+// The field types vary between platforms
+#[allow(clippy::unnecessary_cast)]
+fn block_size(fd: c_int) -> Result<u64, ScanError> {
+    let stat = retry_interrupted(|| with_syscalls(|s| s.fstatvfs(fd)))
+        .map_err(Error::from_raw_os_error)?;
+    match stat.f_frsize as u64 {
+        0 => Ok(stat.f_bsize as u64),
+        fragment => Ok(fragment),
+    }
+}
+
+/// Finds the length of the file, checking first that it is a regular file or
+/// a device, as seeking through anything else fails in platform specific
+/// ways
+///
+/// Some devices report a length of 0 when seeking to their end, so for block
+/// and character devices that do, the length is asked of the driver instead.
+fn file_len(fd: c_int) -> Result<u64, ScanError> {
+    let is_device = match fstat(fd)?.st_mode & libc::S_IFMT {
+        libc::S_IFREG => false,
+        libc::S_IFBLK | libc::S_IFCHR => true,
+        _ => return Err(ScanError::NotARegularFile),
+    };
+
+    let end = safe_lseek(fd, 0, SEEK_END)?.unwrap_or(0);
+    if end == 0 && is_device {
+        device_len(fd)
+    } else {
+        Ok(end)
+    }
+}
+
+/// Asks the driver of a device for its length with the `BLKGETSIZE64` ioctl
+#[cfg(target_os = "linux")]
+fn device_len(fd: c_int) -> Result<u64, ScanError> {
+    // _IOR(0x12, 114, size_t)
+    const BLKGETSIZE64: libc::c_ulong = (2 << 30)
+        | ((std::mem::size_of::<libc::size_t>() as libc::c_ulong) << 16)
+        | (0x12 << 8)
+        | 114;
+
+    let mut len: u64 = 0;
+    let arg = &mut len as *mut u64 as *mut libc::c_void;
+    match unsafe { with_syscalls(|s| s.ioctl(fd, BLKGETSIZE64, arg)) } {
+        Ok(_) => Ok(len),
+        Err(_) => Err(ScanError::UnknownLength),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn device_len(_fd: c_int) -> Result<u64, ScanError> {
+    Err(ScanError::UnknownLength)
+}
+
+/// Copies every extended attribute of `src` to `dst`
+///
+/// A source on a filesystem without extended attributes has none to copy,
+/// but failing to set one on `dst` for that reason is
+/// `Err(ScanError::UnsupportedFileSystem)`.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_xattrs(src: c_int, dst: c_int) -> Result<(), ScanError> {
+    // Each list and get is made once to find the size of buffer needed, then
+    // again to fill it
+    let names = match retry_interrupted(|| with_syscalls(|s| s.flistxattr(src, &mut []))) {
+        Ok(len) => {
+            let mut names = vec![0_u8; len];
+            let len = retry_interrupted(|| with_syscalls(|s| s.flistxattr(src, &mut names)))
+                .map_err(Error::from_raw_os_error)?;
+            names.truncate(len);
+            names
+        }
+        // No attributes can be set on the source, so there are none
+        Err(ENOTSUP) => return Ok(()),
+        Err(errno) => return Err(Error::from_raw_os_error(errno).into()),
+    };
+
+    for name in names.split_inclusive(|&b| b == 0) {
+        let name = CStr::from_bytes_with_nul(name).map_err(|_| ScanError::Raw(EINVAL))?;
+        let len = retry_interrupted(|| with_syscalls(|s| s.fgetxattr(src, name, &mut [])))
+            .map_err(Error::from_raw_os_error)?;
+        let mut value = vec![0_u8; len];
+        let len = retry_interrupted(|| with_syscalls(|s| s.fgetxattr(src, name, &mut value)))
+            .map_err(Error::from_raw_os_error)?;
+        match retry_interrupted(|| with_syscalls(|s| s.fsetxattr(dst, name, &value[..len]))) {
+            Ok(()) => {}
+            Err(ENOTSUP) => return Err(ScanError::UnsupportedFileSystem),
+            Err(errno) => return Err(Error::from_raw_os_error(errno).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Advises the kernel (`MADV_DONTNEED`) that the whole pages of `mmap`
+/// covering `range` are no longer needed, so they are faulted back in from
+/// the file the next time they are read
+#[cfg(feature = "memmap")]
+pub(crate) fn discard_pages(mmap: &mut [u8], range: Range<u64>) -> Result<(), ScanError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let pages = align_inward(range.start..range.end.min(mmap.len() as u64), page_size);
+    if pages.is_empty() {
+        return Ok(());
+    }
+    let pages = &mut mmap[pages.start as usize..pages.end as usize];
+    unsafe {
+        with_syscalls(|s| {
+            s.madvise(
+                pages.as_mut_ptr() as *mut libc::c_void,
+                pages.len(),
+                libc::MADV_DONTNEED,
+            )
+        })
+    }
+    .map_err(|errno| Error::from_raw_os_error(errno).into())
+}
+
+/// How many times a syscall interrupted by a signal is retried before giving up
+const MAX_EINTR_RETRIES: usize = 16;
+
+/// Makes the syscall `f`, trying again if it is interrupted
+fn retry_interrupted<R>(mut f: impl FnMut() -> Result<R, c_int>) -> Result<R, c_int> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            // EINTR indicates that a signal arrived before the syscall
+            // completed, so just try again
+            Err(EINTR) if retries < MAX_EINTR_RETRIES => retries += 1,
+            result => return result,
+        }
+    }
+}
+
+fn safe_lseek(fd: c_int, offset: u64, seek_type: c_int) -> Result<Option<u64>, ScanError> {
+    match retry_interrupted(|| with_syscalls(|s| s.lseek(fd, offset as off_t, seek_type))) {
+        Ok(new_offset) => Ok(Some(new_offset as u64)),
+        // EINVAL indicates that the file system does not support
+        // SEEK_HOLE or SEEK_DATA, so we indicate as such
+        Err(EINVAL) => Err(ScanError::UnsupportedFileSystem),
+        // ENXIO indicates that the the file offset we are looking for
+        // either doesn't exist, or would be beyond the end of the file.
+        // In our case, this just means there is no next segment, so we
+        // return Ok(none) to indicate as such.
+        Err(ENXIO) => Ok(None),
+        // None of the other error codes require special handling, so we
+        // just turn them into an std::io::Error for user friendliness
+        Err(errno) => Err(Error::from_raw_os_error(errno).into()),
+    }
+}
+
+/// The raw syscalls made by this module, returning the errno on failure
+///
+/// Everything goes through [`with_syscalls`] so that tests can swap in an
+/// implementation that fails in chosen ways.
+pub(crate) trait Syscalls {
+    fn lseek(&self, fd: c_int, offset: off_t, whence: c_int) -> Result<off_t, c_int> {
+        // if the return value of lseek is less than 0, an error has occurred
+        match unsafe { libc::lseek(fd, offset, whence) } {
+            // find and deref errno, honestly the scariest thing we do here
+            new_offset if new_offset < 0 => Err(errno().into()),
+            new_offset => Ok(new_offset),
+        }
+    }
+
+    fn fstat(&self, fd: c_int) -> Result<libc::stat, c_int> {
         unsafe {
-            use libc::{fallocate, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
-            use std::io::Error;
-            use std::os::unix::io::AsRawFd;
-
-            if fallocate(
-                self.as_raw_fd(),
-                FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
-                start as libc::off_t,
-                (end - start) as libc::off_t,
-            ) < 0
-            {
-                return Err(Error::last_os_error().into());
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) < 0 {
+                return Err(errno().into());
             }
+            Ok(stat)
         }
-        Ok(())
     }
 
-    #[cfg(target_os = "macos")]
-    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
-        use libc::fcntl;
-        use std::os::unix::io::AsRawFd;
+    fn ftruncate(&self, fd: c_int, len: off_t) -> Result<(), c_int> {
+        match unsafe { libc::ftruncate(fd, len) } {
+            ret if ret < 0 => Err(errno().into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn fstatvfs(&self, fd: c_int) -> Result<libc::statvfs, c_int> {
+        unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::fstatvfs(fd, &mut stat) < 0 {
+                return Err(errno().into());
+            }
+            Ok(stat)
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    fn fallocate(&self, fd: c_int, mode: c_int, offset: off_t, len: off_t) -> Result<(), c_int> {
+        match unsafe { libc::fallocate(fd, mode, offset, len) } {
+            ret if ret < 0 => Err(errno().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `arg` must be valid for the given ioctl `request`
+    #[cfg(target_os = "linux")]
+    unsafe fn ioctl(
+        &self,
+        fd: c_int,
+        request: libc::c_ulong,
+        arg: *mut libc::c_void,
+    ) -> Result<c_int, c_int> {
+        match libc::ioctl(fd, request as _, arg) {
+            ret if ret < 0 => Err(errno().into()),
+            ret => Ok(ret),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `addr` and `len` must cover whole pages of a mapping that nothing else
+    /// is borrowing
+    #[cfg(feature = "memmap")]
+    unsafe fn madvise(
+        &self,
+        addr: *mut libc::c_void,
+        len: usize,
+        advice: c_int,
+    ) -> Result<(), c_int> {
+        match libc::madvise(addr, len, advice) {
+            ret if ret < 0 => Err(errno().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fills `list` with the names of the extended attributes of `fd`, or
+    /// just returns the length needed if `list` is empty
+    #[cfg(target_os = "linux")]
+    fn flistxattr(&self, fd: c_int, list: &mut [u8]) -> Result<usize, c_int> {
+        let ptr = match list.is_empty() {
+            true => std::ptr::null_mut(),
+            false => list.as_mut_ptr() as *mut libc::c_char,
+        };
+        match unsafe { libc::flistxattr(fd, ptr, list.len()) } {
+            len if len < 0 => Err(errno().into()),
+            len => Ok(len as usize),
+        }
+    }
 
+    /// Fills `value` with the extended attribute `name` of `fd`, or just
+    /// returns the length needed if `value` is empty
+    #[cfg(target_os = "linux")]
+    fn fgetxattr(&self, fd: c_int, name: &CStr, value: &mut [u8]) -> Result<usize, c_int> {
+        let ptr = match value.is_empty() {
+            true => std::ptr::null_mut(),
+            false => value.as_mut_ptr() as *mut libc::c_void,
+        };
+        match unsafe { libc::fgetxattr(fd, name.as_ptr(), ptr, value.len()) } {
+            len if len < 0 => Err(errno().into()),
+            len => Ok(len as usize),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fsetxattr(&self, fd: c_int, name: &CStr, value: &[u8]) -> Result<(), c_int> {
+        let ptr = value.as_ptr() as *const libc::c_void;
+        match unsafe { libc::fsetxattr(fd, name.as_ptr(), ptr, value.len(), 0) } {
+            ret if ret < 0 => Err(errno().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// `fcntl(F_PUNCHHOLE)`
+    #[cfg(target_os = "macos")]
+    fn punch_hole(&self, fd: c_int, offset: u64, len: u64) -> Result<(), c_int> {
         #[repr(C)]
         struct fpunchhole_t {
             fp_flags: c_int, /* unused */
@@ -100,43 +665,846 @@ impl SparseFile for File {
         let hole = fpunchhole_t {
             fp_flags: 0,
             reserved: 0,
-            fp_offset: start,
-            fp_length: (end - start),
+            fp_offset: offset,
+            fp_length: len,
         };
+        match unsafe { libc::fcntl(fd, F_PUNCHHOLE, &hole) } {
+            ret if ret < 0 => Err(errno().into()),
+            _ => Ok(()),
+        }
+    }
+}
 
-        // Try to punch the hole
-        unsafe {
-            let ret = fcntl(self.as_raw_fd(), F_PUNCHHOLE, &hole);
-            if ret < 0 {
-                return Err(Error::last_os_error().into());
-            }
+/// The real syscalls
+struct Libc;
+
+impl Syscalls for Libc {}
+
+/// Counts each syscall made before passing it on
+#[cfg(feature = "stats")]
+struct Counted<'a>(&'a dyn Syscalls);
+
+#[cfg(feature = "stats")]
+impl Syscalls for Counted<'_> {
+    fn lseek(&self, fd: c_int, offset: off_t, whence: c_int) -> Result<off_t, c_int> {
+        stats::record(stats::Syscall::Lseek);
+        self.0.lseek(fd, offset, whence)
+    }
+
+    fn fstat(&self, fd: c_int) -> Result<libc::stat, c_int> {
+        stats::record(stats::Syscall::Fstat);
+        self.0.fstat(fd)
+    }
+
+    fn ftruncate(&self, fd: c_int, len: off_t) -> Result<(), c_int> {
+        stats::record(stats::Syscall::Ftruncate);
+        self.0.ftruncate(fd, len)
+    }
+
+    fn fstatvfs(&self, fd: c_int) -> Result<libc::statvfs, c_int> {
+        stats::record(stats::Syscall::Fstatvfs);
+        self.0.fstatvfs(fd)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    fn fallocate(&self, fd: c_int, mode: c_int, offset: off_t, len: off_t) -> Result<(), c_int> {
+        stats::record(stats::Syscall::Fallocate);
+        self.0.fallocate(fd, mode, offset, len)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn ioctl(
+        &self,
+        fd: c_int,
+        request: libc::c_ulong,
+        arg: *mut libc::c_void,
+    ) -> Result<c_int, c_int> {
+        stats::record(stats::Syscall::Ioctl);
+        self.0.ioctl(fd, request, arg)
+    }
+
+    #[cfg(feature = "memmap")]
+    unsafe fn madvise(
+        &self,
+        addr: *mut libc::c_void,
+        len: usize,
+        advice: c_int,
+    ) -> Result<(), c_int> {
+        stats::record(stats::Syscall::Madvise);
+        self.0.madvise(addr, len, advice)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn flistxattr(&self, fd: c_int, list: &mut [u8]) -> Result<usize, c_int> {
+        stats::record(stats::Syscall::Xattr);
+        self.0.flistxattr(fd, list)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fgetxattr(&self, fd: c_int, name: &CStr, value: &mut [u8]) -> Result<usize, c_int> {
+        stats::record(stats::Syscall::Xattr);
+        self.0.fgetxattr(fd, name, value)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fsetxattr(&self, fd: c_int, name: &CStr, value: &[u8]) -> Result<(), c_int> {
+        stats::record(stats::Syscall::Xattr);
+        self.0.fsetxattr(fd, name, value)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn punch_hole(&self, fd: c_int, offset: u64, len: u64) -> Result<(), c_int> {
+        stats::record(stats::Syscall::Fcntl);
+        self.0.punch_hole(fd, offset, len)
+    }
+}
+
+#[cfg(feature = "stats")]
+fn counted<R>(syscalls: &dyn Syscalls, f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    f(&Counted(syscalls))
+}
+
+#[cfg(not(feature = "stats"))]
+fn counted<R>(syscalls: &dyn Syscalls, f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    f(syscalls)
+}
+
+#[cfg(not(test))]
+fn with_syscalls<R>(f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    counted(&Libc, f)
+}
+
+#[cfg(test)]
+thread_local! {
+    static MOCK_SYSCALLS: std::cell::RefCell<Option<Box<dyn Syscalls>>> = Default::default();
+}
+
+#[cfg(test)]
+fn with_syscalls<R>(f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    MOCK_SYSCALLS.with(|mock| match &*mock.borrow() {
+        Some(mock) => counted(mock.as_ref(), f),
+        None => counted(&Libc, f),
+    })
+}
+
+/// Replaces the syscalls made on this thread until the returned guard is
+/// dropped
+#[cfg(test)]
+pub(crate) fn mock_syscalls(mock: impl Syscalls + 'static) -> impl Drop {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            MOCK_SYSCALLS.with(|m| m.borrow_mut().take());
         }
-        Ok(())
     }
+
+    MOCK_SYSCALLS.with(|m| *m.borrow_mut() = Some(Box::new(mock)));
+    Guard
 }
 
-fn safe_lseek(fd: c_int, offset: u64, seek_type: c_int) -> Result<Option<u64>, ScanError> {
-    unsafe {
-        let new_offset = lseek(fd, offset as off_t, seek_type);
-        // if the return value of lseek is less than 0, an error has occurred
-        if new_offset < 0 {
-            // find and deref errno, honestly the scariest thing we do here
-            let errno = errno().into();
-            match errno {
-                // EINVAL indicates that the file system does not support
-                // SEEK_HOLE or SEEK_DATA, so we indicate as such
-                EINVAL => Err(ScanError::UnsupportedFileSystem),
-                // ENXIO indicates that the the file offset we are looking for
-                // either doesn't exist, or would be beyond the end of the file.
-                // In our case, this just means there is no next segment, so we
-                // return Ok(none) to indicate as such.
-                ENXIO => Ok(None),
-                // None of the other error codes require special handling, so we
-                // just turn them into an std::io::Error for user friendliness
-                _ => Err(Error::last_os_error().into()),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A seek layer for a file that claims to be entirely data, like some FUSE
+    // filesystems do regardless of what is actually allocated
+    fn all_data_seek(end: u64) -> impl FnMut(u64, c_int) -> Result<Option<u64>, ScanError> {
+        move |offset, seek_type| match seek_type {
+            SEEK_HOLE => Ok(Some(end)),
+            SEEK_DATA => Ok(Some(offset)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn drops_zero_length_segments() {
+        // Claims data right where the hole it reported starts
+        let seek = |offset, seek_type| match (offset, seek_type) {
+            (0, SEEK_HOLE) => Ok(Some(100)),
+            (100, SEEK_DATA) => Ok(Some(100)),
+            (100, SEEK_HOLE) => Ok(Some(200)),
+            (200, SEEK_DATA) => Ok(Some(250)),
+            (250, SEEK_HOLE) => Ok(None),
+            _ => unreachable!(),
+        };
+        let mut segments = Vec::new();
+        scan_with(0, 300, seek, |s| segments.push(s)).expect("mocked scan");
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0..200),
+                Segment::hole(200..250),
+                Segment::data(250..300),
+            ]
+        );
+
+        // Neither seek ever moves
+        let stuck = |offset, _| Ok(Some(offset));
+        assert!(matches!(
+            scan_with(0, 300, stuck, |_| {}),
+            Err(ScanError::UnsupportedFileSystem)
+        ));
+    }
+
+    #[test]
+    fn scans_files_ending_in_data() {
+        use crate::test_utils::{SparseDescription, BLOCK_SIZE};
+
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![2, 3]);
+        let mut file = desc.to_file();
+        let segments = file.as_file_mut().scan_chunks().expect("scan");
+        assert_eq!(segments, desc.segments());
+        assert_eq!(
+            segments.last(),
+            Some(&Segment::data(2 * BLOCK_SIZE..3 * BLOCK_SIZE))
+        );
+        assert!(segments.iter().all(|s| !s.is_empty()));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn counts_scan_syscalls() {
+        use crate::test_utils::{SparseDescription, BLOCK_SIZE};
+
+        // Data, hole, data, hole
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let mut file = desc.to_file();
+
+        let mut stats = ScanStats::default();
+        file.as_file_mut()
+            .scan_chunks_with_stats(&mut stats)
+            .expect("scan");
+
+        // One to find the end, then one per segment, as the first hole is
+        // sought from the start of the file, after checking the file type
+        assert_eq!(stats.lseek_calls, 1 + 4);
+        assert_eq!(stats.fstat_calls, 1);
+        assert_eq!(stats.scans, 1);
+        assert_eq!(stats.bytes_scanned, 8 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn detects_bogus_seek_layout() {
+        let end = 64 * 1024 * 1024;
+        let mut segments = Vec::new();
+        scan_with(0, end, all_data_seek(end), |s| segments.push(s)).expect("mocked scan");
+
+        assert_eq!(segments, vec![Segment::data(0..end)]);
+        // Nothing like 64MiB is allocated, so the layout can't be right
+        assert!(!is_plausible(&segments, 4096));
+        // Fully allocated, all data is what we would expect
+        assert!(is_plausible(&segments, end));
+    }
+
+    // Reports the whole file as data, regardless of what is allocated
+    struct AllData;
+
+    impl Syscalls for AllData {
+        fn lseek(&self, fd: c_int, offset: off_t, whence: c_int) -> Result<off_t, c_int> {
+            match whence {
+                SEEK_HOLE => Libc.lseek(fd, 0, SEEK_END),
+                SEEK_DATA => Ok(offset),
+                _ => Libc.lseek(fd, offset, whence),
+            }
+        }
+    }
+
+    #[test]
+    fn robust_scan_falls_back_to_content() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        let block_size = file.io_block_size().expect("io block size");
+        let len = 64 * 1024 * 1024;
+        file.write_all(&vec![1; block_size as usize + 1])
+            .expect("write data");
+        file.set_len(len).expect("set length");
+
+        let _mock = mock_syscalls(AllData);
+        let trusting = file.scan_chunks_robust(false).expect("robust scan");
+        let content = file.scan_chunks_robust(true).expect("content scan");
+
+        assert_eq!(trusting, vec![Segment::data(0..len)]);
+        assert_eq!(
+            content,
+            vec![
+                Segment::data(0..2 * block_size),
+                Segment::hole(2 * block_size..len),
+            ]
+        );
+    }
+
+    #[test]
+    fn frees_whole_blocks() {
+        use crate::test_utils::SparseDescription;
+
+        let mut file = SparseDescription::one_segment(SegmentType::Data, 64 * 1024).to_file();
+        let block_size = file.as_file().block_size().expect("block size");
+        assert!(block_size > 0 && (64 * 1024) % block_size == 0);
+
+        // The block before the last is freed, and nothing around it
+        let last = 64 * 1024 - block_size;
+        file.as_file()
+            .drill_hole(last - block_size, last)
+            .expect("drill one block");
+        assert_eq!(
+            file.as_file_mut().scan_chunks().expect("scan"),
+            vec![
+                Segment::data(0..last - block_size),
+                Segment::hole(last - block_size..last),
+                Segment::data(last..64 * 1024),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn drills_unaligned_holes() {
+        use crate::test_utils::SparseDescription;
+        use std::io::Read;
+
+        let mut file = SparseDescription::one_segment(SegmentType::Data, 64 * 1024).to_file();
+        let file = file.as_file_mut();
+        let block_size = file.block_size().expect("block size");
+
+        file.drill_hole(100, 3 * block_size + 100)
+            .expect("drill unaligned hole");
+        file.drill_hole(5 * block_size + 1, 5 * block_size + 2)
+            .expect("drill tiny hole");
+        // An edge that is already a hole isn't written to
+        file.drill_hole(2 * block_size + 1, 3 * block_size)
+            .expect("drill inside hole");
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).expect("seek");
+        file.read_to_end(&mut contents).expect("read back");
+        let zeros = |range: Range<u64>| {
+            contents[range.start as usize..range.end as usize]
+                .iter()
+                .all(|&b| b == 0)
+        };
+        assert!(!zeros(0..100));
+        assert!(zeros(100..3 * block_size + 100));
+        assert!(!zeros(3 * block_size + 100..5 * block_size + 1));
+        assert!(zeros(5 * block_size + 1..5 * block_size + 2));
+        assert!(!zeros(5 * block_size + 2..64 * 1024));
+
+        // Only the whole blocks are freed, the edges are zeroed data
+        assert_eq!(
+            file.scan_chunks().expect("scan"),
+            vec![
+                Segment::data(0..block_size),
+                Segment::hole(block_size..3 * block_size),
+                Segment::data(3 * block_size..64 * 1024),
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_borrowed_fd() {
+        use crate::test_utils::{SparseDescription, BLOCK_SIZE};
+
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let mut file = desc.to_file();
+
+        {
+            let mut borrowed = BorrowedFile::new(file.as_file().as_fd());
+            assert_eq!(borrowed.scan_chunks().expect("scan"), desc.segments());
+            borrowed.drill_hole(0, 2 * BLOCK_SIZE).expect("drill hole");
+        }
+
+        // The file is still open and sees the hole
+        let expected = SparseDescription::from_parts(SegmentType::Hole, vec![3, 5, 8]);
+        assert_eq!(
+            file.as_file_mut().scan_chunks().expect("scan"),
+            expected.segments()
+        );
+    }
+
+    #[test]
+    fn scans_wrapped_files() {
+        use crate::test_utils::SparseDescription;
+
+        struct Wrapper(File);
+
+        impl Read for Wrapper {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl Seek for Wrapper {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        impl AsRawFd for Wrapper {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0.as_raw_fd()
             }
-        } else {
-            Ok(Some(new_offset as u64))
         }
+
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 4, 6]);
+        let mut wrapper = Wrapper(desc.to_file().reopen().expect("reopen"));
+        assert_eq!(wrapper.scan_chunks().expect("scan"), desc.segments());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reflinks_or_reports_unsupported() {
+        use crate::test_utils::SparseDescription;
+
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 8]);
+        let src = desc.to_file();
+        let mut dst = tempfile::NamedTempFile::new_in(src.path().parent().expect("temp dir"))
+            .expect("Unable to create tempfile");
+
+        match src.as_file().reflink_to(dst.as_file()) {
+            Ok(()) => assert_eq!(
+                dst.as_file_mut().scan_chunks().expect("scan clone"),
+                desc.segments()
+            ),
+            // e.g. ext4 and tmpfs
+            Err(ScanError::UnsupportedFileSystem) => {}
+            Err(e) => panic!("reflink failed: {:?}", e),
+        }
+    }
+
+    // Fails every call to fallocate with `ENOSPC`
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    struct DiskFull;
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    impl Syscalls for DiskFull {
+        fn fallocate(
+            &self,
+            _fd: c_int,
+            _mode: c_int,
+            _offset: off_t,
+            _len: off_t,
+        ) -> Result<(), c_int> {
+            Err(libc::ENOSPC)
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    #[test]
+    fn maps_enospc_to_out_of_space() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let _mock = mock_syscalls(DiskFull);
+        assert!(matches!(file.reserve(0, 4096), Err(ScanError::OutOfSpace)));
+        assert!(matches!(
+            file.drill_hole(0, 4096),
+            Err(ScanError::OutOfSpace)
+        ));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
+    #[test]
+    fn reserves_space() {
+        use crate::test_utils::{SparseDescription, BLOCK_SIZE};
+        use std::io::Read;
+
+        let mut file = SparseDescription::one_segment(SegmentType::Hole, 8 * BLOCK_SIZE).to_file();
+        let file = file.as_file_mut();
+        let before = file.sparse_len().expect("sparse len");
+
+        file.reserve(2 * BLOCK_SIZE, 4 * BLOCK_SIZE)
+            .expect("reserve");
+        file.reserve(8 * BLOCK_SIZE, BLOCK_SIZE)
+            .expect("reserve past end");
+        // Nothing to reserve, even past the end
+        file.reserve(20 * BLOCK_SIZE, 0).expect("reserve nothing");
+
+        assert!(file.sparse_len().expect("sparse len") >= before + 5 * BLOCK_SIZE);
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).expect("read back");
+        assert_eq!(contents.len() as u64, 9 * BLOCK_SIZE);
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+
+    #[cfg(target_os = "macos")]
+    struct NoPunchHole;
+
+    #[cfg(target_os = "macos")]
+    impl Syscalls for NoPunchHole {
+        fn punch_hole(&self, _fd: c_int, _offset: u64, _len: u64) -> Result<(), c_int> {
+            Err(EINVAL)
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn maps_punch_hole_einval() {
+        use crate::test_utils::SparseDescription;
+
+        let file = SparseDescription::one_segment(SegmentType::Data, 64 * 1024).to_file();
+        let file = file.as_file();
+        let block_size = file.block_size().expect("block size");
+
+        let _mock = mock_syscalls(NoPunchHole);
+        assert!(matches!(
+            file.drill_hole(0, 2 * block_size),
+            Err(ScanError::UnsupportedFileSystem)
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lists_physical_extents() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&[1; 8192]).expect("write data");
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 65536, 4096) };
+        assert_eq!(ret, 0, "preallocate: {}", Error::last_os_error());
+
+        let extents = match file.physical_extents() {
+            Err(ScanError::UnsupportedFileSystem) => return,
+            extents => extents.expect("physical extents"),
+        };
+
+        let covers = |offset| {
+            extents
+                .iter()
+                .find(|e| e.logical <= offset && offset < e.logical + e.length)
+        };
+        assert!(covers(0).is_some_and(|e| !e.flags.unwritten));
+        assert!(covers(65536).is_some_and(|e| e.flags.unwritten));
+        assert!(covers(32768).is_none());
+        assert!(extents.windows(2).all(|w| w[0].logical < w[1].logical));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn flags_preallocated_extents_unwritten() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 4096, 8192) };
+        assert_eq!(ret, 0, "preallocate: {}", Error::last_os_error());
+
+        let segments = match file.scan_chunks_detailed() {
+            Err(ScanError::UnsupportedFileSystem) => return,
+            segments => segments.expect("detailed scan"),
+        };
+
+        let unwritten = ExtentFlags {
+            unwritten: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            segments,
+            vec![
+                DetailedSegment {
+                    segment: Segment::hole(0..4096),
+                    flags: ExtentFlags::default(),
+                },
+                DetailedSegment {
+                    segment: Segment::data(4096..12288),
+                    flags: unwritten,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_directories() {
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let mut file = File::open(dir.path()).expect("open directory");
+        assert!(matches!(
+            file.scan_chunks(),
+            Err(ScanError::NotARegularFile)
+        ));
+        assert!(matches!(
+            file.scan_chunks_range(0..4096),
+            Err(ScanError::NotARegularFile)
+        ));
+        assert!(matches!(
+            file.scan_chunks_iter().next(),
+            Some(Err(ScanError::NotARegularFile))
+        ));
+    }
+
+    // Pretends the file is a device that reports a length of 0 when seeking
+    // to its end, and has the given length if asked with BLKGETSIZE64
+    #[cfg(target_os = "linux")]
+    struct Device {
+        mode: libc::mode_t,
+        len: Option<u64>,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Syscalls for Device {
+        fn lseek(&self, _fd: c_int, offset: off_t, whence: c_int) -> Result<off_t, c_int> {
+            match whence {
+                SEEK_END => Ok(0),
+                SEEK_HOLE => Ok(self.len.unwrap_or(0) as off_t),
+                _ => Ok(offset),
+            }
+        }
+
+        fn fstat(&self, fd: c_int) -> Result<libc::stat, c_int> {
+            let mut stat = Libc.fstat(fd)?;
+            stat.st_mode = self.mode;
+            Ok(stat)
+        }
+
+        unsafe fn ioctl(
+            &self,
+            _fd: c_int,
+            _request: libc::c_ulong,
+            arg: *mut libc::c_void,
+        ) -> Result<c_int, c_int> {
+            match self.len {
+                Some(len) => {
+                    *(arg as *mut u64) = len;
+                    Ok(0)
+                }
+                None => Err(libc::ENOTTY),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn asks_devices_for_their_length() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let mock = mock_syscalls(Device {
+            mode: libc::S_IFBLK,
+            len: Some(1 << 20),
+        });
+        assert_eq!(
+            file.scan_chunks().expect("scan block device"),
+            vec![Segment::data(0..1 << 20)]
+        );
+        drop(mock);
+
+        let _mock = mock_syscalls(Device {
+            mode: libc::S_IFCHR,
+            len: None,
+        });
+        assert!(matches!(file.scan_chunks(), Err(ScanError::UnknownLength)));
+    }
+
+    // Needs a loop device set up by hand (e.g. `losetup -f --show image`)
+    // and permission to read it, named by DRILL_PRESS_TEST_LOOP_DEVICE
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn scans_loop_device() {
+        let path = match std::env::var_os("DRILL_PRESS_TEST_LOOP_DEVICE") {
+            Some(path) => path,
+            None => return,
+        };
+        let mut device = File::open(path).expect("open loop device");
+        let len = device_len(device.as_raw_fd()).expect("device length");
+
+        match device.scan_chunks() {
+            Ok(segments) => {
+                assert_eq!(segments.first().map(|s| s.range.start), Some(0));
+                assert_eq!(segments.last().map(|s| s.range.end), Some(len));
+            }
+            // Few block devices support SEEK_HOLE
+            Err(ScanError::UnsupportedFileSystem) => {}
+            Err(e) => panic!("scan failed: {:?}", e),
+        }
+    }
+
+    // Fails the first `failures` calls to lseek with `errno`, then behaves
+    // normally
+    struct FailingLseek {
+        errno: c_int,
+        failures: std::cell::Cell<usize>,
+    }
+
+    impl Syscalls for FailingLseek {
+        fn lseek(&self, fd: c_int, offset: off_t, whence: c_int) -> Result<off_t, c_int> {
+            match self.failures.get() {
+                0 => Libc.lseek(fd, offset, whence),
+                n => {
+                    self.failures.set(n - 1);
+                    Err(self.errno)
+                }
+            }
+        }
+    }
+
+    fn failing_lseek(errno: c_int, failures: usize) -> impl Drop {
+        mock_syscalls(FailingLseek {
+            errno,
+            failures: failures.into(),
+        })
+    }
+
+    #[test]
+    fn retries_interrupted_seeks() {
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.set_len(4096).expect("set length");
+
+        let _mock = failing_lseek(EINTR, 2);
+        let segments = file.scan_chunks().expect("scan despite EINTR");
+
+        assert_eq!(segments, vec![Segment::hole(0..4096)]);
+    }
+
+    // Interrupts the first `failures` calls to fallocate, then behaves
+    // normally
+    #[cfg(target_os = "linux")]
+    struct InterruptedFallocate {
+        failures: std::cell::Cell<usize>,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Syscalls for InterruptedFallocate {
+        fn fallocate(
+            &self,
+            fd: c_int,
+            mode: c_int,
+            offset: off_t,
+            len: off_t,
+        ) -> Result<(), c_int> {
+            match self.failures.get() {
+                0 => Libc.fallocate(fd, mode, offset, len),
+                n => {
+                    self.failures.set(n - 1);
+                    Err(EINTR)
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn retries_interrupted_drills() {
+        use crate::test_utils::{SparseDescription, BLOCK_SIZE};
+
+        let desc = SparseDescription::one_segment(SegmentType::Data, 2 * BLOCK_SIZE);
+        let mut file = desc.to_file();
+
+        let mock = mock_syscalls(InterruptedFallocate { failures: 2.into() });
+        file.as_file()
+            .drill_hole(0, BLOCK_SIZE)
+            .expect("drill despite EINTR");
+        drop(mock);
+
+        let expected = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2]);
+        assert_eq!(
+            file.as_file_mut().scan_chunks().expect("scan"),
+            expected.segments()
+        );
+
+        let _mock = mock_syscalls(InterruptedFallocate {
+            failures: (MAX_EINTR_RETRIES + 1).into(),
+        });
+        assert!(matches!(
+            file.as_file().drill_hole(BLOCK_SIZE, 2 * BLOCK_SIZE),
+            Err(ScanError::IO(e)) if e.raw_os_error() == Some(EINTR)
+        ));
+    }
+
+    struct FailingFtruncate(c_int);
+
+    impl Syscalls for FailingFtruncate {
+        fn ftruncate(&self, _fd: c_int, _len: off_t) -> Result<(), c_int> {
+            Err(self.0)
+        }
+    }
+
+    #[test]
+    fn maps_extend_errors() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let mock = mock_syscalls(FailingFtruncate(libc::EIO));
+        match file.extend_with_hole(4096) {
+            Err(ScanError::IO(e)) => assert_eq!(e.raw_os_error(), Some(libc::EIO)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        drop(mock);
+        assert_eq!(file.metadata().expect("metadata").len(), 0);
+
+        let _mock = mock_syscalls(FailingFtruncate(libc::ENOSPC));
+        assert!(matches!(
+            file.extend_with_hole(4096),
+            Err(ScanError::OutOfSpace)
+        ));
+    }
+
+    #[test]
+    fn maps_seek_errors() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+        let fd = file.as_raw_fd();
+
+        let mock = failing_lseek(EINVAL, 1);
+        assert!(matches!(
+            safe_lseek(fd, 0, SEEK_HOLE),
+            Err(ScanError::UnsupportedFileSystem)
+        ));
+        drop(mock);
+
+        let mock = failing_lseek(ENXIO, 1);
+        assert!(matches!(safe_lseek(fd, 0, SEEK_DATA), Ok(None)));
+        drop(mock);
+
+        let _mock = failing_lseek(libc::EIO, 1);
+        match safe_lseek(fd, 0, SEEK_DATA) {
+            Err(ScanError::IO(e)) => assert_eq!(e.raw_os_error(), Some(libc::EIO)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// A source with one attribute, `user.test`, which `dst` can't store
+    #[cfg(target_os = "linux")]
+    struct NoXattrs {
+        /// Whether the source can't list its attributes either
+        src_unsupported: bool,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Syscalls for NoXattrs {
+        fn flistxattr(&self, _fd: c_int, list: &mut [u8]) -> Result<usize, c_int> {
+            const NAMES: &[u8] = b"user.test\0";
+            if self.src_unsupported {
+                return Err(ENOTSUP);
+            }
+            if let Some(l) = list.get_mut(..NAMES.len()) {
+                l.copy_from_slice(NAMES);
+            }
+            Ok(NAMES.len())
+        }
+
+        fn fgetxattr(&self, _fd: c_int, name: &CStr, value: &mut [u8]) -> Result<usize, c_int> {
+            assert_eq!(name.to_bytes(), b"user.test");
+            if let Some(v) = value.get_mut(..5) {
+                v.copy_from_slice(b"value");
+            }
+            Ok(5)
+        }
+
+        fn fsetxattr(&self, _fd: c_int, _name: &CStr, value: &[u8]) -> Result<(), c_int> {
+            assert_eq!(value, b"value");
+            Err(ENOTSUP)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reports_unsupported_xattrs() {
+        let src = tempfile::tempfile().expect("Unable to create tempfile");
+        let dst = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let mock = mock_syscalls(NoXattrs {
+            src_unsupported: false,
+        });
+        assert!(matches!(
+            copy_xattrs(src.as_raw_fd(), dst.as_raw_fd()),
+            Err(ScanError::UnsupportedFileSystem)
+        ));
+        drop(mock);
+
+        let _mock = mock_syscalls(NoXattrs {
+            src_unsupported: true,
+        });
+        copy_xattrs(src.as_raw_fd(), dst.as_raw_fd()).expect("nothing to copy");
     }
 }
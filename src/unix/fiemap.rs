@@ -0,0 +1,137 @@
+//! `FS_IOC_FIEMAP` based extent mapping, which unlike `SEEK_HOLE`/`SEEK_DATA`
+//! reports how each extent is stored on disk
+use super::*;
+
+use libc::{c_ulong, c_void, EOPNOTSUPP};
+
+const FS_IOC_FIEMAP: c_ulong = 0xC020_660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_DATA_ENCRYPTED: u32 = 0x80;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// How many extents to ask for in each call
+const EXTENTS_PER_CALL: usize = 256;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FiemapHeader {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct FiemapExtent {
+    pub fe_logical: u64,
+    pub fe_physical: u64,
+    pub fe_length: u64,
+    fe_reserved64: [u64; 2],
+    pub fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    header: FiemapHeader,
+    extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+/// Lists every extent of the file, in logical order
+pub(crate) fn extents(fd: c_int) -> Result<Vec<FiemapExtent>, ScanError> {
+    let mut extents = Vec::new();
+    // All zeros is a valid value for every field of the request
+    let mut request: Box<Fiemap> = Box::new(unsafe { std::mem::zeroed() });
+    let mut start = 0;
+
+    loop {
+        request.header = FiemapHeader {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENTS_PER_CALL as u32,
+            fm_reserved: 0,
+        };
+
+        let arg = &mut *request as *mut Fiemap as *mut c_void;
+        unsafe { with_syscalls(|s| s.ioctl(fd, FS_IOC_FIEMAP, arg)) }.map_err(
+            |errno| match errno {
+                EOPNOTSUPP => ScanError::UnsupportedFileSystem,
+                _ => Error::from_raw_os_error(errno).into(),
+            },
+        )?;
+
+        let mapped = &request.extents[..request.header.fm_mapped_extents as usize];
+        extents.extend_from_slice(mapped);
+        match mapped.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.fe_logical + last.fe_length;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+impl From<u32> for ExtentFlags {
+    fn from(fe_flags: u32) -> Self {
+        ExtentFlags {
+            unwritten: fe_flags & FIEMAP_EXTENT_UNWRITTEN != 0,
+            shared: fe_flags & FIEMAP_EXTENT_SHARED != 0,
+            encrypted: fe_flags & FIEMAP_EXTENT_DATA_ENCRYPTED != 0,
+        }
+    }
+}
+
+impl From<&FiemapExtent> for PhysicalExtent {
+    fn from(extent: &FiemapExtent) -> Self {
+        PhysicalExtent {
+            logical: extent.fe_logical,
+            physical: extent.fe_physical,
+            length: extent.fe_length,
+            flags: extent.fe_flags.into(),
+        }
+    }
+}
+
+/// Builds the detailed layout of a file of length `len` from its extents,
+/// filling the gaps between them with holes
+pub(crate) fn detailed_segments(extents: &[FiemapExtent], len: u64) -> Vec<DetailedSegment> {
+    let mut segments: Vec<DetailedSegment> = Vec::with_capacity(extents.len() * 2 + 1);
+    let mut push = |segment_type, range: Range<u64>, flags| {
+        if range.is_empty() {
+            return;
+        }
+        match segments.last_mut() {
+            Some(last) if last.segment.segment_type == segment_type && last.flags == flags => {
+                last.segment.range.end = range.end;
+            }
+            _ => segments.push(DetailedSegment {
+                segment: Segment {
+                    segment_type,
+                    range,
+                },
+                flags,
+            }),
+        }
+    };
+
+    let mut prev_end = 0;
+    for extent in extents {
+        // Preallocated extents can extend beyond the end of the file
+        let start = extent.fe_logical.min(len);
+        let end = (extent.fe_logical + extent.fe_length).min(len);
+        push(SegmentType::Hole, prev_end..start, ExtentFlags::default());
+        push(SegmentType::Data, start..end, extent.fe_flags.into());
+        prev_end = end;
+    }
+    push(SegmentType::Hole, prev_end..len, ExtentFlags::default());
+
+    segments
+}
@@ -1,68 +1,138 @@
 use super::*;
 
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
 
 use winapi::shared::minwindef::{DWORD, LPVOID};
-use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+use winapi::shared::winerror::ERROR_MORE_DATA;
+use winapi::um::fileapi::{
+    GetDiskFreeSpaceW, GetFileInformationByHandle, GetFinalPathNameByHandleW, GetVolumePathNameW,
+    SetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION, FILE_END_OF_FILE_INFO,
+};
 use winapi::um::ioapiset::DeviceIoControl;
-use winapi::um::winioctl::{FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_ZERO_DATA};
+use winapi::um::minwinbase::FileEndOfFileInfo;
+use winapi::um::winioctl::{FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA};
 use winapi::um::winnt::FILE_ATTRIBUTE_SPARSE_FILE;
 
 use std::mem::MaybeUninit;
 
-impl SparseFile for File {
+impl<T: Read + Seek + AsRawHandle> SparseFile for T {
     fn scan_chunks(&mut self) -> std::result::Result<std::vec::Vec<Segment>, ScanError> {
-        // Get the length before doing anything
-        let len = self.seek(SeekFrom::End(0))?;
+        self.scan_chunks_from(0)
+    }
+
+    fn scan_chunks_range(&mut self, range: Range<u64>) -> Result<Vec<Segment>, ScanError> {
+        let offset = range.start;
+        // Get the length before doing anything, only scanning as far as the
+        // end of the range
+        let len = self.seek(SeekFrom::End(0))?.min(range.end);
         // get the handle from the file
         let handle = self.as_raw_handle();
-        // First check for an empty file
-        if len == 0 {
+        // First check for an empty file, or if we are starting past the end
+        if len <= offset {
             // Return nothing here, an empty file has no ranges
             Ok(vec![])
         } else if is_sparse(handle)? {
             // Call through and get the allocated ranges
-            let ranges = get_allocated_ranges(handle, len)?;
+            let ranges = get_allocated_ranges(handle, offset..len)?;
             // Make a place to put our segments, and copy over our ranges
 
-            let mut prev_end = 0;
+            let mut prev_end = offset;
             let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
 
             for range in ranges {
-                let end = range.offset + range.length;
-                if prev_end != range.offset {
-                    segments.push(Segment {
-                        segment_type: SegmentType::Hole,
-                        range: prev_end..range.offset,
-                    });
+                let start = range.offset.max(offset);
+                let end = (range.offset + range.length).min(len);
+                if prev_end != start {
+                    segments.push(Segment::hole(prev_end..start));
                 }
-                segments.push(Segment {
-                    segment_type: SegmentType::Data,
-                    range: range.offset..end,
-                });
+                segments.push(Segment::data(start..end));
                 prev_end = end;
             }
 
             // Check to see if we need to add a hole segment at the end
             if prev_end < len {
-                segments.push(Segment {
-                    segment_type: SegmentType::Hole,
-                    range: prev_end..len,
-                });
+                segments.push(Segment::hole(prev_end..len));
             }
 
             Ok(segments)
         } else {
-            Ok(vec![Segment {
-                segment_type: SegmentType::Data,
-                range: 0..len,
-            }])
+            Ok(vec![Segment::data(offset..len)])
+        }
+    }
+
+    fn seek_data(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        let len = self.seek(SeekFrom::End(0))?;
+        let data = if offset >= len {
+            None
+        } else if is_sparse(self.as_raw_handle())? {
+            get_allocated_ranges(self.as_raw_handle(), offset..len)?
+                .first()
+                .map(|range| range.offset.max(offset))
+        } else {
+            Some(offset)
+        };
+
+        // Leave the seek position where lseek would
+        if let Some(data) = data {
+            self.seek(SeekFrom::Start(data))?;
+        }
+        Ok(data)
+    }
+
+    fn seek_hole(&mut self, offset: u64) -> Result<Option<u64>, ScanError> {
+        let len = self.seek(SeekFrom::End(0))?;
+        let hole = if offset >= len {
+            None
+        } else if is_sparse(self.as_raw_handle())? {
+            // The hole starts where the run of ranges touching `offset` ends,
+            // or at the end of the file, which counts as a hole
+            let mut hole = offset;
+            for range in get_allocated_ranges(self.as_raw_handle(), offset..len)? {
+                if range.offset > hole {
+                    break;
+                }
+                hole = hole.max(range.offset + range.length);
+            }
+            Some(hole.min(len))
+        } else {
+            Some(len)
+        };
+
+        if let Some(hole) = hole {
+            self.seek(SeekFrom::Start(hole))?;
         }
+        Ok(hole)
+    }
+
+    fn is_sparse(&self) -> Result<bool, ScanError> {
+        is_sparse(self.as_raw_handle())
+    }
+
+    fn set_sparse(&self) -> Result<(), ScanError> {
+        unsafe {
+            device_io_control(
+                self.as_raw_handle(),
+                FSCTL_SET_SPARSE,
+                &FileSetSparseBuffer { set_sparse: 1 },
+                std::ptr::null_mut::<()>(),
+                0,
+            )?;
+        }
+        Ok(())
     }
 
     fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        check_range(start, end)?;
+        // The offsets are signed LARGE_INTEGERs to Windows
+        if end > i64::MAX as u64 {
+            return Err(ScanError::InvalidRange { start, end });
+        }
+        if start == end {
+            return Ok(());
+        }
         unsafe {
             device_io_control(
                 self.as_raw_handle(),
@@ -71,12 +141,36 @@ impl SparseFile for File {
                     offset: start,
                     beyond_final_zero: end,
                 },
-                std::ptr::null_mut() as *mut (),
+                std::ptr::null_mut::<()>(),
                 0,
             )?;
         };
         Ok(())
     }
+
+    fn extend_with_hole(&self, additional: u64) -> Result<(), ScanError> {
+        self.set_sparse()?;
+        // Never dropped, as the handle belongs to `self`
+        let file = ManuallyDrop::new(unsafe { File::from_raw_handle(self.as_raw_handle()) });
+        let len = file.metadata()?.len().saturating_add(additional);
+        with_syscalls(|s| s.set_end_of_file(self.as_raw_handle(), len))
+            .map_err(|code| allocation_error(std::io::Error::from_raw_os_error(code)))
+    }
+
+    fn io_block_size(&self) -> Result<u64, ScanError> {
+        Ok(64 * 1024)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        // NTFS allocates sparse files in compression units of 16 clusters
+        Ok(cluster_size(self.as_raw_handle())? * 16)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileSetSparseBuffer {
+    set_sparse: u8,
 }
 
 // Define some types
@@ -95,60 +189,251 @@ struct FileAllocatedRange {
     length: u64,
 }
 
-/// Get the portions of a file that contain data
+/// How many ranges to ask for in each call
+const RANGES_PER_CALL: usize = 1024;
+
+/// Get the portions of a range of a file that contain data
 fn get_allocated_ranges(
     handle: RawHandle,
-    size: u64,
+    range: Range<u64>,
 ) -> Result<Vec<FileAllocatedRange>, ScanError> {
-    let mut ranges = Vec::with_capacity(1024);
+    let mut ranges = Vec::new();
+    let mut buffer: Vec<FileAllocatedRange> = Vec::with_capacity(RANGES_PER_CALL);
+    let mut start = range.start;
 
-    unsafe {
-        // Check the returned value
-        // FIXME: WIll error if the user provides a massive file with too many ranges
-        // Really need to check for MORE_DATA and do a loop
-        let returned_bytes = device_io_control(
-            handle,
-            FSCTL_QUERY_ALLOCATED_RANGES,
-            &FileAllocatedRange {
-                offset: 0,
-                length: size,
-            },
-            ranges.as_mut_ptr(),
-            ranges.capacity() * std::mem::size_of::<FileAllocatedRange>(),
-        )?;
-
-        ranges.set_len(returned_bytes / std::mem::size_of::<FileAllocatedRange>());
-    };
+    while start < range.end {
+        let more = unsafe {
+            let (returned_bytes, more) = device_io_control(
+                handle,
+                FSCTL_QUERY_ALLOCATED_RANGES,
+                &FileAllocatedRange {
+                    offset: start,
+                    length: range.end - start,
+                },
+                buffer.as_mut_ptr(),
+                buffer.capacity() * std::mem::size_of::<FileAllocatedRange>(),
+            )?;
+            buffer.set_len(returned_bytes / std::mem::size_of::<FileAllocatedRange>());
+            more
+        };
+
+        ranges.extend_from_slice(&buffer);
+        match buffer.last() {
+            // Carry on from the end of the last range that fitted
+            Some(last) if more => start = last.offset + last.length,
+            _ => break,
+        }
+    }
 
     Ok(ranges)
 }
 
 /// a wrapper round
+///
+/// Returns the number of bytes written to `result`, and whether there was
+/// more to return than would fit (`ERROR_MORE_DATA`)
 unsafe fn device_io_control<Q: Sized, R: Sized>(
     handle: RawHandle,
     control_code: DWORD,
     query: &Q,
     result: *mut R,
     capacity: usize,
-) -> Result<usize, ScanError> {
+) -> Result<(usize, bool), ScanError> {
     let mut returned_bytes: DWORD = 0;
 
-    let ret = DeviceIoControl(
-        handle as _,
-        control_code,
-        query as *const _ as LPVOID,
-        std::mem::size_of::<Q>() as DWORD,
-        result as LPVOID,
-        capacity as DWORD,
-        &mut returned_bytes,
-        std::ptr::null_mut(),
-    );
+    let more = match with_syscalls(|s| {
+        s.device_io_control(
+            handle,
+            control_code,
+            query as *const _ as LPVOID,
+            std::mem::size_of::<Q>() as DWORD,
+            result as LPVOID,
+            capacity as DWORD,
+            &mut returned_bytes,
+        )
+    }) {
+        Ok(()) => false,
+        Err(code) if code == ERROR_MORE_DATA as i32 => true,
+        Err(code) => return Err(allocation_error(std::io::Error::from_raw_os_error(code))),
+    };
 
-    if ret == 0 {
-        return Err(std::io::Error::last_os_error().into());
+    Ok((returned_bytes as usize, more))
+}
+
+/// The raw syscalls made by this module, returning the os error code on
+/// failure
+///
+/// Everything goes through [`with_syscalls`] so that tests can swap in an
+/// implementation that fails in chosen ways.
+pub(crate) trait Syscalls {
+    /// # Safety
+    ///
+    /// The buffers must be valid for reads and writes of the given sizes
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn device_io_control(
+        &self,
+        handle: RawHandle,
+        control_code: DWORD,
+        in_buffer: LPVOID,
+        in_size: DWORD,
+        out_buffer: LPVOID,
+        out_size: DWORD,
+        returned_bytes: &mut DWORD,
+    ) -> Result<(), i32> {
+        let ret = DeviceIoControl(
+            handle as _,
+            control_code,
+            in_buffer,
+            in_size,
+            out_buffer,
+            out_size,
+            returned_bytes,
+            std::ptr::null_mut(),
+        );
+
+        if ret == 0 {
+            return Err(std::io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or_default());
+        }
+        Ok(())
     }
 
-    Ok(returned_bytes as usize)
+    /// Sets the length of the file with `SetFileInformationByHandle`
+    fn set_end_of_file(&self, handle: RawHandle, len: u64) -> Result<(), i32> {
+        let mut info: FILE_END_OF_FILE_INFO = unsafe { std::mem::zeroed() };
+        unsafe { *info.EndOfFile.QuadPart_mut() = len as i64 };
+        let ret = unsafe {
+            SetFileInformationByHandle(
+                handle as _,
+                FileEndOfFileInfo,
+                &mut info as *mut FILE_END_OF_FILE_INFO as LPVOID,
+                std::mem::size_of::<FILE_END_OF_FILE_INFO>() as DWORD,
+            )
+        };
+
+        if ret == 0 {
+            return Err(std::io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+/// The real syscalls
+struct Kernel32;
+
+impl Syscalls for Kernel32 {}
+
+/// Counts each syscall made before passing it on
+#[cfg(feature = "stats")]
+struct Counted<'a>(&'a dyn Syscalls);
+
+#[cfg(feature = "stats")]
+impl Syscalls for Counted<'_> {
+    unsafe fn device_io_control(
+        &self,
+        handle: RawHandle,
+        control_code: DWORD,
+        in_buffer: LPVOID,
+        in_size: DWORD,
+        out_buffer: LPVOID,
+        out_size: DWORD,
+        returned_bytes: &mut DWORD,
+    ) -> Result<(), i32> {
+        stats::record(stats::Syscall::DeviceIoControl);
+        self.0.device_io_control(
+            handle,
+            control_code,
+            in_buffer,
+            in_size,
+            out_buffer,
+            out_size,
+            returned_bytes,
+        )
+    }
+
+    fn set_end_of_file(&self, handle: RawHandle, len: u64) -> Result<(), i32> {
+        stats::record(stats::Syscall::SetFileInformation);
+        self.0.set_end_of_file(handle, len)
+    }
+}
+
+#[cfg(feature = "stats")]
+fn counted<R>(syscalls: &dyn Syscalls, f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    f(&Counted(syscalls))
+}
+
+#[cfg(not(feature = "stats"))]
+fn counted<R>(syscalls: &dyn Syscalls, f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    f(syscalls)
+}
+
+#[cfg(not(test))]
+fn with_syscalls<R>(f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    counted(&Kernel32, f)
+}
+
+#[cfg(test)]
+thread_local! {
+    static MOCK_SYSCALLS: std::cell::RefCell<Option<Box<dyn Syscalls>>> = Default::default();
+}
+
+#[cfg(test)]
+fn with_syscalls<R>(f: impl FnOnce(&dyn Syscalls) -> R) -> R {
+    MOCK_SYSCALLS.with(|mock| match &*mock.borrow() {
+        Some(mock) => counted(mock.as_ref(), f),
+        None => counted(&Kernel32, f),
+    })
+}
+
+/// Replaces the syscalls made on this thread until the returned guard is
+/// dropped
+#[cfg(test)]
+pub(crate) fn mock_syscalls(mock: impl Syscalls + 'static) -> impl Drop {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            MOCK_SYSCALLS.with(|m| m.borrow_mut().take());
+        }
+    }
+
+    MOCK_SYSCALLS.with(|m| *m.borrow_mut() = Some(Box::new(mock)));
+    Guard
+}
+
+/// Finds the cluster size of the volume holding the file
+fn cluster_size(handle: RawHandle) -> Result<u64, ScanError> {
+    // Long enough for any path, including the \\?\ prefix
+    let mut path = vec![0_u16; 32 * 1024];
+    let mut volume = vec![0_u16; path.len()];
+    let mut sectors_per_cluster: DWORD = 0;
+    let mut bytes_per_sector: DWORD = 0;
+    let mut free_clusters: DWORD = 0;
+    let mut total_clusters: DWORD = 0;
+
+    unsafe {
+        let len = GetFinalPathNameByHandleW(handle as _, path.as_mut_ptr(), path.len() as DWORD, 0);
+        if len == 0 || len as usize >= path.len() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if GetVolumePathNameW(path.as_ptr(), volume.as_mut_ptr(), volume.len() as DWORD) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if GetDiskFreeSpaceW(
+            volume.as_ptr(),
+            &mut sectors_per_cluster,
+            &mut bytes_per_sector,
+            &mut free_clusters,
+            &mut total_clusters,
+        ) == 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(sectors_per_cluster as u64 * bytes_per_sector as u64)
 }
 
 /// Check if the file is sparse
@@ -167,3 +452,202 @@ fn is_sparse(handle: RawHandle) -> Result<bool, ScanError> {
     let file_info = unsafe { file_info.assume_init() };
     Ok(file_info.dwFileAttributes & FILE_ATTRIBUTE_SPARSE_FILE != 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::SparseDescription;
+
+    use quickcheck_macros::quickcheck;
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_DISK_FULL};
+
+    // Rebuilds the layout the way portable code probing with
+    // SEEK_DATA/SEEK_HOLE on unix would
+    fn probe_layout(file: &mut File) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while let Some(hole) = file.seek_hole(offset).expect("seek hole") {
+            if hole > offset {
+                segments.push(Segment::data(offset..hole));
+            }
+            let len = file.seek(SeekFrom::End(0)).expect("seek end");
+            let data = file.seek_data(hole).expect("seek data").unwrap_or(len);
+            if data > hole {
+                segments.push(Segment::hole(hole..data));
+            }
+            offset = data;
+        }
+        segments
+    }
+
+    #[quickcheck]
+    fn seeks_match_scan(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        // NTFS may report adjacent allocated ranges separately
+        let segments = coalesced(file.scan_chunks().expect("scan chunks"));
+        probe_layout(file) == segments
+    }
+
+    // Fails every call with the given error code
+    struct FailingIoControl(i32);
+
+    impl Syscalls for FailingIoControl {
+        unsafe fn device_io_control(
+            &self,
+            _handle: RawHandle,
+            _control_code: DWORD,
+            _in_buffer: LPVOID,
+            _in_size: DWORD,
+            _out_buffer: LPVOID,
+            _out_size: DWORD,
+            _returned_bytes: &mut DWORD,
+        ) -> Result<(), i32> {
+            Err(self.0)
+        }
+    }
+
+    // Reports every other 64K of the file as allocated, a few ranges at a time
+    struct Fragmented {
+        per_call: usize,
+    }
+
+    impl Syscalls for Fragmented {
+        unsafe fn device_io_control(
+            &self,
+            _handle: RawHandle,
+            _control_code: DWORD,
+            in_buffer: LPVOID,
+            _in_size: DWORD,
+            out_buffer: LPVOID,
+            out_size: DWORD,
+            returned_bytes: &mut DWORD,
+        ) -> Result<(), i32> {
+            let query = *(in_buffer as *const FileAllocatedRange);
+            let out = out_buffer as *mut FileAllocatedRange;
+            let capacity =
+                (out_size as usize / std::mem::size_of::<FileAllocatedRange>()).min(self.per_call);
+
+            let chunk = 64 * 1024;
+            let mut offset = query.offset / (2 * chunk) * (2 * chunk);
+            if query.offset >= offset + chunk {
+                offset += 2 * chunk;
+            }
+            let mut count = 0;
+            while offset < query.offset + query.length {
+                if count == capacity {
+                    *returned_bytes = (count * std::mem::size_of::<FileAllocatedRange>()) as DWORD;
+                    return Err(ERROR_MORE_DATA as i32);
+                }
+                let start = offset.max(query.offset);
+                *out.add(count) = FileAllocatedRange {
+                    offset: start,
+                    length: offset + chunk - start,
+                };
+                count += 1;
+                offset += 2 * chunk;
+            }
+            *returned_bytes = (count * std::mem::size_of::<FileAllocatedRange>()) as DWORD;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn gets_ranges_beyond_one_call() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+        let chunk = 64 * 1024;
+
+        let _mock = mock_syscalls(Fragmented { per_call: 3 });
+        let ranges = get_allocated_ranges(file.as_raw_handle(), 0..20 * chunk).expect("ranges");
+
+        let ranges: Vec<_> = ranges.iter().map(|r| (r.offset, r.length)).collect();
+        let expected: Vec<_> = (0..10).map(|i| (2 * i * chunk, chunk)).collect();
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn maps_device_io_control_errors() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let mock = mock_syscalls(FailingIoControl(ERROR_ACCESS_DENIED as i32));
+        match file.drill_hole(0, 4096) {
+            Err(ScanError::IO(e)) => assert_eq!(e.raw_os_error(), Some(ERROR_ACCESS_DENIED as i32)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        drop(mock);
+
+        let _mock = mock_syscalls(FailingIoControl(ERROR_DISK_FULL as i32));
+        assert!(matches!(
+            file.drill_hole(0, 4096),
+            Err(ScanError::OutOfSpace)
+        ));
+    }
+
+    struct FailingSetEndOfFile(i32);
+
+    impl Syscalls for FailingSetEndOfFile {
+        fn set_end_of_file(&self, _handle: RawHandle, _len: u64) -> Result<(), i32> {
+            Err(self.0)
+        }
+    }
+
+    #[test]
+    fn maps_extend_errors() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        let _mock = mock_syscalls(FailingSetEndOfFile(ERROR_DISK_FULL as i32));
+        assert!(matches!(
+            file.extend_with_hole(4096),
+            Err(ScanError::OutOfSpace)
+        ));
+        assert_eq!(file.metadata().expect("metadata").len(), 0);
+    }
+
+    #[test]
+    fn validates_drill_ranges() {
+        let file = tempfile::tempfile().expect("Unable to create tempfile");
+
+        // None of these reach FSCTL_SET_ZERO_DATA
+        let _mock = mock_syscalls(FailingIoControl(ERROR_ACCESS_DENIED as i32));
+        file.drill_hole(4096, 4096).expect("empty drill");
+        assert!(matches!(
+            file.drill_hole(4096, 0),
+            Err(ScanError::InvalidRange {
+                start: 4096,
+                end: 0
+            })
+        ));
+        assert!(matches!(
+            file.drill_hole(0, u64::MAX),
+            Err(ScanError::InvalidRange { .. })
+        ));
+    }
+
+    #[test]
+    fn punches_zeros_in_dense_files() {
+        use std::io::Write;
+
+        let chunk = 64 * 1024;
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&vec![1; chunk]).expect("write data");
+        file.write_all(&vec![0; 2 * chunk]).expect("write zeros");
+        file.write_all(&vec![1; chunk]).expect("write data");
+        file.sync_all().expect("sync");
+        assert!(!file.is_sparse().expect("is sparse"));
+
+        let freed = file.punch_zeros(chunk as u64).expect("punch zeros");
+        assert_eq!(freed, 2 * chunk as u64);
+        assert!(file.is_sparse().expect("is sparse"));
+
+        let chunk = chunk as u64;
+        assert_eq!(
+            file.scan_chunks().expect("scan"),
+            vec![
+                Segment::data(0..chunk),
+                Segment::hole(chunk..3 * chunk),
+                Segment::data(3 * chunk..4 * chunk),
+            ]
+        );
+    }
+}